@@ -14,4 +14,4 @@
 #![allow(clippy::redundant_pattern_matching)]
 #![allow(clippy::nonminimal_bool)]
 
-mod utils;
+pub mod utils;
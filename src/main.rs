@@ -17,14 +17,36 @@
 
 mod utils;
 
-use clap::{self, arg, Command};
+use clap::{self, arg, ArgAction, Command};
 use colored::Colorize;
-use std::{fs, fs::File, io::Write, process::exit};
+use std::{fs, fs::File, io::Write, path::Path, process::exit};
 
 fn main() {
-    let key_arg = || {
-        arg!(--key <KEY> "Encryption passphrase (overrides LSON_KEY env var)").required(false)
+    let key_arg =
+        || arg!(--key <KEY> "Encryption passphrase (overrides LSON_KEY env var)").required(false);
+    let profile_arg = || arg!(--profile <PROFILE> "Apply a profile: its @profile blocks and <file>.<profile>.kson");
+    let overlay_arg = || {
+        arg!(--overlay <FILE> "Merge a kson file over the document (repeatable)")
+            .action(ArgAction::Append)
     };
+    let env_prefix_arg = || arg!(--"env-prefix" <PREFIX> "Override keys from env vars such as PREFIX__DATABASE__POOL_MAX");
+    let env_file_arg = || {
+        arg!(--"env-file" <FILE> "Read env vars from this file (repeatable; replaces ./.env)")
+            .action(ArgAction::Append)
+    };
+    let no_dotenv_arg = || arg!(--"no-dotenv" "Do not read ./.env, only the process environment");
+    let bare_env_arg =
+        || arg!(--"bare-env" "Accept bare @env names as values (deprecated, use env(NAME))");
+    let reveal_arg = || arg!(--reveal "Print env and secret values instead of ***");
+    let unknown_keys_arg = || {
+        arg!(--"unknown-keys" <POLICY> "Keys the KModel does not declare: strict | warn | allow")
+            .value_parser(["strict", "warn", "allow"])
+    };
+    let redact_arg = || arg!(--redact "Write @secret properties as *** in JSON output");
+    let allow_cmd_secrets_arg =
+        || arg!(--"allow-cmd-secrets" "Let @secret(cmd:...) run shell commands");
+    let allow_http_secrets_arg =
+        || arg!(--"allow-http-secrets" "Let @secret(http://...) fetch over the network");
 
     let raw = Command::new("raw")
         .about("Raw commands mode (output to stdout)")
@@ -36,11 +58,19 @@ fn main() {
                         .required_unless_present("text"),
                 )
                 .arg(arg!(--text <TEXT> "The kson text to compile").required_unless_present("file"))
-                .arg(
-                    arg!(-t --output_type <TYPE> "Output type: json | lson")
-                        .default_value("lson"),
-                )
+                .arg(arg!(-t --output_type <TYPE> "Output type: json | lson").default_value("lson"))
                 .arg(arg!(--kmodel <KMODEL> "The kmodel file to validate against"))
+                .arg(profile_arg())
+                .arg(overlay_arg())
+                .arg(env_file_arg())
+                .arg(no_dotenv_arg())
+                .arg(env_prefix_arg())
+                .arg(bare_env_arg())
+                .arg(unknown_keys_arg())
+                .arg(allow_cmd_secrets_arg())
+                .arg(allow_http_secrets_arg())
+                .arg(reveal_arg())
+                .arg(redact_arg())
                 .arg(key_arg())
                 .arg_required_else_help(true),
         )
@@ -67,6 +97,17 @@ fn main() {
                         .default_value("lson"),
                 )
                 .arg(arg!(--kmodel <KMODEL> "The kmodel file to validate against"))
+                .arg(profile_arg())
+                .arg(overlay_arg())
+                .arg(env_file_arg())
+                .arg(no_dotenv_arg())
+                .arg(env_prefix_arg())
+                .arg(bare_env_arg())
+                .arg(unknown_keys_arg())
+                .arg(allow_cmd_secrets_arg())
+                .arg(allow_http_secrets_arg())
+                .arg(reveal_arg())
+                .arg(redact_arg())
                 .arg(key_arg())
                 .arg_required_else_help(true),
         )
@@ -80,7 +121,9 @@ fn main() {
             Command::new("parse")
                 .about("Decrypt and print an lson file")
                 .arg(arg!(<file> "The lson file to decrypt").required(true))
+                .arg(arg!(--kmodel <KMODEL> "KModel whose @secret properties are masked (defaults to @model)"))
                 .arg(key_arg())
+                .arg(reveal_arg())
                 .arg_required_else_help(true),
         )
         .subcommand(
@@ -88,6 +131,8 @@ fn main() {
                 .about("Check whether a kson file matches the sealed hash inside an lson file (no key needed)")
                 .arg(arg!(-f --file <FILE> "The source kson file").required(true))
                 .arg(arg!(--lson <LSON> "The compiled lson file").required(true))
+                .arg(profile_arg())
+                .arg(overlay_arg())
                 .arg_required_else_help(true),
         )
         .subcommand(
@@ -96,6 +141,16 @@ fn main() {
                 .arg(arg!(-f --file <FILE> "The kson file to lock").required(true))
                 .arg(arg!(-o --output <OUTPUT> "Output .lock file (defaults to <file>.lock)"))
                 .arg(arg!(--kmodel <KMODEL> "The kmodel file to validate against"))
+                .arg(profile_arg())
+                .arg(overlay_arg())
+                .arg(env_file_arg())
+                .arg(no_dotenv_arg())
+                .arg(env_prefix_arg())
+                .arg(bare_env_arg())
+                .arg(unknown_keys_arg())
+                .arg(allow_cmd_secrets_arg())
+                .arg(allow_http_secrets_arg())
+                .arg(reveal_arg())
                 .arg_required_else_help(true),
         )
         .arg(arg!(-h --help "Show this help message"))
@@ -103,6 +158,8 @@ fn main() {
 
     let matches = menu.clone().get_matches();
 
+    utils::kson::diagnostics::init_color();
+
     if *matches.get_one::<bool>("version").unwrap_or(&false) {
         println!("🔒 LSON - v{}", env!("CARGO_PKG_VERSION"));
         exit(0);
@@ -116,11 +173,13 @@ fn main() {
             let file = arg_m.get_one::<String>("file");
             let text = arg_m.get_one::<String>("text");
             let output_type = arg_m.get_one::<String>("output_type").unwrap();
-            let kmodel = arg_m.get_one::<String>("kmodel");
             let explicit_key = arg_m.get_one::<String>("key").map(|s| s.as_str());
 
             if output_type != "json" && output_type != "lson" {
-                eprintln!("Invalid output type '{}' — choose json or lson", output_type);
+                eprintln!(
+                    "Invalid output type '{}' — choose json or lson",
+                    output_type
+                );
                 exit(1);
             }
 
@@ -141,17 +200,12 @@ fn main() {
                     exit(1);
                 });
 
-                eprintln!("{}", "Deriving key with Argon2id (this takes a moment)…".bright_black());
+                eprintln!(
+                    "{}",
+                    "Deriving key with Argon2id (this takes a moment)…".bright_black()
+                );
 
-                let lson_result = if let Some(f) = file {
-                    utils::lson::encrypt_file(f, &key)
-                } else {
-                    utils::lson::encrypt(text.unwrap(), &key)
-                }
-                .unwrap_or_else(|e| {
-                    eprintln!("{}: {}", "error".red().bold(), e);
-                    exit(1);
-                });
+                let lson_result = seal_lson(arg_m, file, text, &key, verbose);
 
                 let mut out_file = File::create(&out).unwrap_or_else(|e| {
                     eprintln!("{}: {}", "error".red().bold(), e);
@@ -160,13 +214,11 @@ fn main() {
                 out_file.write_all(lson_result.as_bytes()).unwrap();
                 println!("{}: {}", "Sealed LSON →".green(), out.yellow());
             } else {
-                let json_result = if let Some(f) = file {
-                    utils::kson::kson_items_to_json(
-                        utils::kson::read_file(f, kmodel, verbose).unwrap(),
-                    )
-                } else {
-                    utils::kson::kson_items_to_json(utils::kson::read(text.unwrap(), kmodel, verbose))
-                };
+                let mut document = read_document(arg_m, file, text, verbose);
+                if arg_m.get_flag("redact") {
+                    document.redact_secrets();
+                }
+                let json_result = utils::kson::kson_items_to_json(&document.properties);
 
                 let mut out_file = File::create(&out).unwrap_or_else(|e| {
                     eprintln!("{}: {}", "error".red().bold(), e);
@@ -200,6 +252,23 @@ fn main() {
                 exit(1);
             });
 
+            let plaintext = if arg_m.get_flag("reveal") {
+                plaintext
+            } else {
+                let model = arg_m
+                    .get_one::<String>("kmodel")
+                    .map(|m| m.as_str())
+                    .or_else(|| utils::kson::model_path(&plaintext))
+                    .map(|m| {
+                        utils::kson::kmodel::read(m, verbose).unwrap_or_else(|e| {
+                            eprint!("{}", utils::kson::diagnostics::render_all(&[e], None));
+                            exit(1);
+                        })
+                    });
+
+                utils::kson::redact::source(&plaintext, model.as_ref())
+            };
+
             println!("{}\n{}", "── DECRYPTED KSON ──".green().bold(), plaintext);
         }
 
@@ -208,13 +277,13 @@ fn main() {
             let kson_file = arg_m.get_one::<String>("file").unwrap();
             let lson_file = arg_m.get_one::<String>("lson").unwrap();
 
-            let kson_content = fs::read_to_string(kson_file).unwrap_or_else(|e| {
-                eprintln!("{}: cannot read '{}': {}", "error".red().bold(), kson_file, e);
-                exit(1);
-            });
-
             let lson_content = fs::read_to_string(lson_file).unwrap_or_else(|e| {
-                eprintln!("{}: cannot read '{}': {}", "error".red().bold(), lson_file, e);
+                eprintln!(
+                    "{}: cannot read '{}': {}",
+                    "error".red().bold(),
+                    lson_file,
+                    e
+                );
                 exit(1);
             });
 
@@ -223,7 +292,15 @@ fn main() {
                 exit(1);
             });
 
-            let current_hash = utils::lson::sha256_hex(kson_content.as_bytes());
+            // Covers every file the source @includes, like the sealed hash.
+            let current_hash = utils::kson::include::source_hash(
+                kson_file,
+                &overlay_files(arg_m, Some(kson_file)),
+            )
+            .unwrap_or_else(|e| {
+                eprint!("{}", utils::kson::diagnostics::render_all(&[e], None));
+                exit(1);
+            });
 
             if current_hash == sealed_hash {
                 println!(
@@ -245,16 +322,15 @@ fn main() {
         // ── lock ──────────────────────────────────────────────────────────────
         Some(("lock", arg_m)) => {
             let file = arg_m.get_one::<String>("file").unwrap();
-            let kmodel = arg_m.get_one::<String>("kmodel");
-
-            let kson_content = fs::read_to_string(file).unwrap_or_else(|e| {
-                eprintln!("{}: {}", "error".red().bold(), e);
-                exit(1);
-            });
 
-            let json_result = utils::kson::kson_items_to_json(
-                utils::kson::read_file(file, kmodel, verbose).unwrap(),
-            );
+            let mut document = utils::kson::read_file(file, &read_options(arg_m, verbose))
+                .unwrap_or_else(|e| {
+                    eprint!("{}", utils::kson::diagnostics::render_all(&e, None));
+                    exit(1);
+                });
+            // The lock is meant to be committed, so @secret values never go in clear.
+            document.redact_secrets();
+            let json_result = utils::kson::kson_items_to_json(&document.properties);
 
             // Re-serialise through serde_json to guarantee sorted, canonical keys.
             let canonical = match serde_json::from_str::<serde_json::Value>(&json_result) {
@@ -262,7 +338,12 @@ fn main() {
                 Err(_) => json_result,
             };
 
-            let kson_hash = utils::lson::sha256_hex(kson_content.as_bytes());
+            let kson_hash =
+                utils::kson::include::source_hash(file, &overlay_files(arg_m, Some(file)))
+                    .unwrap_or_else(|e| {
+                        eprint!("{}", utils::kson::diagnostics::render_all(&[e], None));
+                        exit(1);
+                    });
 
             let out = arg_m
                 .get_one::<String>("output")
@@ -289,7 +370,6 @@ fn main() {
                 let file = sub.get_one::<String>("file");
                 let text = sub.get_one::<String>("text");
                 let output_type = sub.get_one::<String>("output_type").unwrap();
-                let kmodel = sub.get_one::<String>("kmodel");
                 let explicit_key = sub.get_one::<String>("key").map(|s| s.as_str());
 
                 if name == "compile" {
@@ -298,29 +378,13 @@ fn main() {
                             eprintln!("{}", e.to_string().red());
                             exit(1);
                         });
-                        let result = if let Some(f) = file {
-                            utils::lson::encrypt_file(f, &key)
-                        } else {
-                            utils::lson::encrypt(text.unwrap(), &key)
-                        }
-                        .unwrap_or_else(|e| {
-                            eprintln!("{}: {}", "error".red().bold(), e);
-                            exit(1);
-                        });
-                        print!("{}", result);
+                        print!("{}", seal_lson(sub, file, text, &key, verbose));
                     } else {
-                        let result = if let Some(f) = file {
-                            utils::kson::kson_items_to_json(
-                                utils::kson::read_file(f, kmodel, verbose).unwrap(),
-                            )
-                        } else {
-                            utils::kson::kson_items_to_json(utils::kson::read(
-                                text.unwrap(),
-                                kmodel,
-                                verbose,
-                            ))
-                        };
-                        print!("{}", result);
+                        let mut document = read_document(sub, file, text, verbose);
+                        if sub.get_flag("redact") {
+                            document.redact_secrets();
+                        }
+                        print!("{}", utils::kson::kson_items_to_json(&document.properties));
                     }
                 }
             } else {
@@ -333,8 +397,158 @@ fn main() {
             let _ = menu.print_help();
         }
         None => {
-            println!("{}", "🔒 LSON — Type-safe encrypted configuration".cyan().bold());
+            println!(
+                "{}",
+                "🔒 LSON — Type-safe encrypted configuration".cyan().bold()
+            );
             let _ = menu.print_help();
         }
     }
 }
+
+/// Flags that shape the document read from a kson file. With any of them,
+/// `-t lson` seals the resolved document instead of the source as written.
+const READ_FLAGS: &[&str] = &[
+    "profile",
+    "overlay",
+    "env-file",
+    "no-dotenv",
+    "env-prefix",
+    "bare-env",
+    "unknown-keys",
+    "redact",
+    "allow-cmd-secrets",
+    "allow-http-secrets",
+];
+
+/// The `<file>.<profile>.kson` of `--profile` and the `--overlay` files.
+fn overlay_files(arg_m: &clap::ArgMatches, file: Option<&String>) -> Vec<String> {
+    let overlays: Vec<String> = arg_m
+        .get_many::<String>("overlay")
+        .map(|files| files.cloned().collect())
+        .unwrap_or_default();
+
+    utils::kson::overlay_files(
+        file.map(|f| f.as_str()),
+        arg_m.get_one::<String>("profile").map(|p| p.as_str()),
+        &overlays,
+    )
+}
+
+/// Read the `--file` or `--text` document, or print its errors and exit.
+fn read_document(
+    arg_m: &clap::ArgMatches,
+    file: Option<&String>,
+    text: Option<&String>,
+    verbose: bool,
+) -> utils::kson::Document {
+    if let Some(f) = file {
+        utils::kson::read_file(f, &read_options(arg_m, verbose))
+    } else {
+        utils::kson::read(text.unwrap(), &read_options(arg_m, verbose))
+    }
+    .unwrap_or_else(|e| {
+        eprint!(
+            "{}",
+            utils::kson::diagnostics::render_all(&e, text.map(|t| t.as_str()))
+        );
+        exit(1);
+    })
+}
+
+/// The LSON file for `-t lson`. A document that `@include`s other files, or
+/// is read with any of the [`READ_FLAGS`], is sealed resolved, and its
+/// KSON-HASH covers the included and overlay files.
+fn seal_lson(
+    arg_m: &clap::ArgMatches,
+    file: Option<&String>,
+    text: Option<&String>,
+    key: &str,
+    verbose: bool,
+) -> String {
+    let source = match file {
+        Some(f) => fs::read_to_string(f).unwrap_or_else(|e| {
+            eprintln!("{}: cannot read '{}': {}", "error".red().bold(), f, e);
+            exit(1);
+        }),
+        None => text.unwrap().to_string(),
+    };
+
+    let resolved = utils::kson::include::uses_include(&source)
+        || READ_FLAGS
+            .iter()
+            .any(|id| arg_m.value_source(id) == Some(clap::parser::ValueSource::CommandLine));
+
+    let sealed = if resolved {
+        let document = read_document(arg_m, file, text, verbose);
+        let overlays = overlay_files(arg_m, file);
+        let kson_hash = match file {
+            Some(f) => utils::kson::include::source_hash(f, &overlays),
+            None => utils::kson::include::text_hash(&source, &overlays),
+        }
+        .unwrap_or_else(|e| {
+            eprint!("{}", utils::kson::diagnostics::render_all(&[e], None));
+            exit(1);
+        });
+
+        utils::lson::seal_document(&document, &kson_hash, key)
+    } else {
+        utils::lson::seal(&source, &utils::lson::sha256_hex(source.as_bytes()), key)
+    };
+
+    sealed.unwrap_or_else(|e| {
+        eprintln!("{}: {}", "error".red().bold(), e);
+        exit(1);
+    })
+}
+
+/// Options for reading a kson file, from the flags shared by compile, raw compile and lock.
+fn read_options(arg_m: &clap::ArgMatches, verbose: bool) -> utils::kson::ReadOptions {
+    utils::kson::ReadOptions {
+        kmodel: arg_m.get_one::<String>("kmodel").cloned(),
+        verbose,
+        bare_env: arg_m.get_flag("bare-env"),
+        profile: arg_m.get_one::<String>("profile").cloned(),
+        overlays: arg_m
+            .get_many::<String>("overlay")
+            .map(|files| files.cloned().collect())
+            .unwrap_or_default(),
+        env_prefix: arg_m.get_one::<String>("env-prefix").cloned(),
+        env: env_source(arg_m),
+        secrets: secret_providers(arg_m),
+        reveal: arg_m.get_flag("reveal"),
+        unknown_keys: arg_m
+            .get_one::<String>("unknown-keys")
+            .and_then(|policy| utils::kson::kmodel::UnknownKeys::parse(policy)),
+    }
+}
+
+/// The default secret providers, plus `cmd` and `http` when allowed.
+fn secret_providers(arg_m: &clap::ArgMatches) -> utils::kson::SecretProviders {
+    let mut providers = utils::kson::SecretProviders::default();
+
+    if arg_m.get_flag("allow-cmd-secrets") {
+        providers.register("cmd", utils::kson::secret::CommandProvider);
+    }
+    if arg_m.get_flag("allow-http-secrets") {
+        providers.register("http", utils::kson::secret::HttpProvider);
+    }
+
+    providers
+}
+
+/// `--env-file`s if given; otherwise `./.env` when it exists, unless `--no-dotenv`.
+fn env_source(arg_m: &clap::ArgMatches) -> utils::kson::EnvSource {
+    let files: Vec<String> = arg_m
+        .get_many::<String>("env-file")
+        .map(|files| files.cloned().collect())
+        .unwrap_or_default();
+
+    if !files.is_empty() {
+        utils::kson::EnvSource::Files(files)
+    } else if !arg_m.get_flag("no-dotenv") && Path::new(".env").is_file() {
+        utils::kson::EnvSource::Files(vec![".env".to_string()])
+    } else {
+        utils::kson::EnvSource::Process
+    }
+}
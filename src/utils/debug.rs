@@ -8,6 +8,7 @@ pub fn debug(verbose: bool, message: &str) {
     }
 }
 
+/// Warnings go to stderr so they never end up in `raw` output.
 pub fn warn(message: &str) {
-    println!("{} {}", "warning".yellow(), message);
+    eprintln!("{} {}", "warning".yellow(), message);
 }
@@ -0,0 +1,250 @@
+#![allow(dead_code)]
+//! Value constraints written after a KModel type:
+//!
+//! ```text
+//! port: Integer(1..=65535)
+//! name: String(len 1..64)
+//! host: String(/^[a-z0-9.-]+$/)
+//! tags: Array<String>(len ..10, unique)
+//! ```
+
+use regex::Regex;
+use std::fmt;
+
+use super::value::KsonValue;
+
+#[derive(Debug)]
+pub enum Constraint {
+    /// `1..=65535`: bounds on a number.
+    Range(Range),
+    /// `len 1..64`: bounds on the length of a string, in characters, or of an array.
+    Len(Range),
+    /// `/^[a-z]+$/`: a pattern a string must match.
+    Pattern(Regex),
+    /// `unique`: the elements of an array are distinct.
+    Unique,
+}
+
+/// `a..b`, `a..=b`, `a..` or `..b`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Whether `max` itself is allowed (`..=`).
+    pub inclusive: bool,
+}
+
+impl Range {
+    fn parse(text: &str) -> Option<Range> {
+        let (min, max, inclusive) = match text.split_once("..=") {
+            Some((min, max)) => (min, max, true),
+            None => {
+                let (min, max) = text.split_once("..")?;
+                (min, max, false)
+            }
+        };
+
+        let bound = |b: &str| match b.trim() {
+            "" => Some(None),
+            b => b.parse::<f64>().ok().map(Some),
+        };
+        let range = Range {
+            min: bound(min)?,
+            max: bound(max)?,
+            inclusive,
+        };
+
+        // `a..=` has nothing to include.
+        (!(inclusive && range.max.is_none())).then_some(range)
+    }
+
+    pub fn contains(&self, n: f64) -> bool {
+        let above_min = self.min.is_none_or(|min| n >= min);
+        let below_max = match self.max {
+            Some(max) if self.inclusive => n <= max,
+            Some(max) => n < max,
+            None => true,
+        };
+
+        above_min && below_max
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(min) = self.min {
+            write!(f, "{min}")?;
+        }
+        write!(f, "{}", if self.inclusive { "..=" } else { ".." })?;
+        if let Some(max) = self.max {
+            write!(f, "{max}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constraint::Range(range) => write!(f, "{range}"),
+            Constraint::Len(range) => write!(f, "len {range}"),
+            Constraint::Pattern(regex) => write!(f, "/{}/", regex.as_str()),
+            Constraint::Unique => write!(f, "unique"),
+        }
+    }
+}
+
+impl Constraint {
+    /// Whether `value` satisfies the constraint. Values of another kind, e.g.
+    /// `null` for an optional property, are left to the type check.
+    pub fn allows(&self, value: &KsonValue) -> bool {
+        match (self, value) {
+            (Constraint::Range(range), KsonValue::Integer(i)) => range.contains(*i as f64),
+            (Constraint::Range(range), KsonValue::Float(x)) => range.contains(*x),
+            (Constraint::Len(range), KsonValue::String(s)) => {
+                range.contains(s.chars().count() as f64)
+            }
+            (Constraint::Len(range), KsonValue::Array(elements)) => {
+                range.contains(elements.len() as f64)
+            }
+            (Constraint::Pattern(regex), KsonValue::String(s)) => regex.is_match(s),
+            (Constraint::Unique, KsonValue::Array(elements)) => elements
+                .iter()
+                .enumerate()
+                .all(|(i, element)| !elements[..i].contains(element)),
+            _ => true,
+        }
+    }
+
+    /// Whether the constraint means anything for values of type `base`
+    /// (`Integer`, `Float`, `String` or `Array`).
+    pub fn applies_to(&self, base: &str) -> bool {
+        match self {
+            Constraint::Range(_) => matches!(base, "Integer" | "Float"),
+            Constraint::Len(_) => matches!(base, "String" | "Array"),
+            Constraint::Pattern(_) => base == "String",
+            Constraint::Unique => base == "Array",
+        }
+    }
+}
+
+/// Parse the comma-separated constraints between the parentheses of `Integer(...)`.
+pub fn parse_list(text: &str) -> Option<Vec<Constraint>> {
+    let mut constraints = vec![];
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        let (constraint, after) = match rest.strip_prefix('/') {
+            // A pattern runs to the next unescaped `/`, commas included.
+            Some(pattern) => {
+                let end = pattern_end(pattern)?;
+                let regex = Regex::new(&pattern[..end]).ok()?;
+                (Constraint::Pattern(regex), &pattern[end + 1..])
+            }
+            None => {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let item = rest[..end].trim();
+                let constraint = match item.strip_prefix("len") {
+                    Some(range) if range.starts_with(char::is_whitespace) => {
+                        Constraint::Len(Range::parse(range)?)
+                    }
+                    _ if item == "unique" => Constraint::Unique,
+                    _ => Constraint::Range(Range::parse(item)?),
+                };
+                (constraint, &rest[end..])
+            }
+        };
+
+        constraints.push(constraint);
+
+        rest = after.trim_start();
+        rest = match rest.strip_prefix(',') {
+            Some(next) => next.trim_start(),
+            None if rest.is_empty() => rest,
+            None => return None,
+        };
+    }
+
+    (!constraints.is_empty()).then_some(constraints)
+}
+
+/// Byte index of the `/` closing a pattern.
+fn pattern_end(pattern: &str) -> Option<usize> {
+    let mut escaped = false;
+
+    for (i, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '/' => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Vec<String> {
+        parse_list(text)
+            .unwrap()
+            .iter()
+            .map(|constraint| constraint.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parsed("1..=65535"), ["1..=65535"]);
+        assert_eq!(parsed("0.5.."), ["0.5.."]);
+        assert_eq!(parsed("..10"), ["..10"]);
+    }
+
+    #[test]
+    fn parses_several_constraints() {
+        assert_eq!(parsed("len 1..64, unique"), ["len 1..64", "unique"]);
+        assert_eq!(parsed(" len ..=3 ,unique "), ["len ..=3", "unique"]);
+        assert_eq!(parsed("1..2,"), ["1..2"]);
+    }
+
+    #[test]
+    fn keeps_commas_inside_patterns() {
+        assert_eq!(
+            parsed(r"/^[a,b]{1,3}$/, len ..4"),
+            ["/^[a,b]{1,3}$/", "len ..4"]
+        );
+        assert_eq!(parsed(r"/a\/b/"), [r"/a\/b/"]);
+    }
+
+    #[test]
+    fn rejects_malformed_lists() {
+        assert!(parse_list("1..=").is_none());
+        assert!(parse_list("big").is_none());
+        assert!(parse_list("len1..2").is_none());
+        assert!(parse_list("/[a-/").is_none());
+        assert!(parse_list("/unclosed").is_none());
+        assert!(parse_list("1..2 unique").is_none());
+    }
+
+    #[test]
+    fn checks_values() {
+        let constraints = parse_list("1..=65535").unwrap();
+        let port = &constraints[0];
+
+        assert!(port.allows(&KsonValue::Integer(65535)));
+        assert!(!port.allows(&KsonValue::Integer(65536)));
+        assert!(!port.allows(&KsonValue::Integer(0)));
+        assert!(port.allows(&KsonValue::Null));
+
+        let constraints = parse_list("unique").unwrap();
+        let array = |elements: &[i64]| {
+            KsonValue::Array(elements.iter().map(|&i| KsonValue::Integer(i)).collect())
+        };
+
+        assert!(constraints[0].allows(&array(&[1, 2, 3])));
+        assert!(!constraints[0].allows(&array(&[1, 2, 1])));
+    }
+}
@@ -0,0 +1,142 @@
+#![allow(dead_code)]
+//! Renders KSON / KModel errors with the offending source line and a caret
+//! under the bad span, in the style of compiler diagnostics:
+//!
+//! ```text
+//! error: invalid value for property: port = "abc", expected value of type: Integer
+//!   --> app.kson:12:12
+//!    |
+//! 12 |     port = "abc"
+//!    |            ^^^^^
+//!    |
+//! note: type declared here
+//!   --> app.kmodel:11:5
+//!    |
+//! 11 |     port: Integer
+//!    |     ^^^^^^^^^^^^^
+//! ```
+
+use colored::{control, Colorize};
+use std::{
+    env, fs,
+    io::{self, IsTerminal},
+};
+
+use super::error::{KsonError, Span};
+use super::redact::MASK;
+
+/// Turn colour output off when `NO_COLOR` is set or stdout is not a terminal.
+pub fn init_color() {
+    if env::var_os("NO_COLOR").is_some() || !io::stdout().is_terminal() {
+        control::set_override(false);
+    }
+}
+
+/// Render `error` as a multi-line diagnostic.
+///
+/// `text` is the source of the document when it did not come from a file
+/// (`--text`); sources with a path are read from disk.
+pub fn render(error: &KsonError, text: Option<&str>) -> String {
+    let mut out = format!("{}: {}\n", "error".red().bold(), error.kind);
+
+    out.push_str(&snippet(
+        error.file.as_deref(),
+        error.span(),
+        text,
+        error.masked,
+        |s| s.red().bold().to_string(),
+    ));
+
+    if let Some(note) = &error.note {
+        out.push_str(&format!("{}: {}\n", "note".cyan().bold(), note.message));
+        out.push_str(&snippet(
+            note.file.as_deref(),
+            note.span,
+            None,
+            false,
+            |s| s.cyan().bold().to_string(),
+        ));
+    }
+
+    out
+}
+
+/// Render every error in `errors`, followed by a summary count.
+pub fn render_all(errors: &[KsonError], text: Option<&str>) -> String {
+    let mut out = String::new();
+
+    for error in errors {
+        out.push_str(&render(error, text));
+        out.push('\n');
+    }
+
+    let plural = if errors.len() == 1 { "" } else { "s" };
+    out.push_str(&format!(
+        "{}: aborting due to {} previous error{}\n",
+        "error".red().bold(),
+        errors.len(),
+        plural
+    ));
+
+    out
+}
+
+/// The source line at `span` with carets under the span. With `masked`, the
+/// spanned text itself is replaced by `***`.
+fn snippet(
+    file: Option<&str>,
+    span: Span,
+    text: Option<&str>,
+    masked: bool,
+    paint: impl Fn(&str) -> String,
+) -> String {
+    let name = file.unwrap_or("<text>");
+
+    // Line 0 means the error is about the file as a whole — there is nothing to point at.
+    if span.line == 0 {
+        return format!("  {} {}\n", "-->".blue().bold(), name);
+    }
+
+    let mut out = format!(
+        "  {} {}:{}:{}\n",
+        "-->".blue().bold(),
+        name,
+        span.line,
+        span.column
+    );
+
+    let source = match file {
+        Some(path) => fs::read_to_string(path).ok(),
+        None => text.map(|t| t.to_string()),
+    };
+
+    let Some(line) = source.as_deref().and_then(|s| s.lines().nth(span.line - 1)) else {
+        return out;
+    };
+
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let bar = "|".blue().bold();
+
+    // Tabs would shift the caret, so draw the line with spaces instead.
+    let mut line = line.replace('\t', " ");
+    let mut len = span.len;
+
+    if masked {
+        let start = span.column.saturating_sub(1);
+        let before: String = line.chars().take(start).collect();
+        let after: String = line.chars().skip(start + span.len).collect();
+        line = format!("{before}{MASK}{after}");
+        len = MASK.len();
+    }
+
+    let padding = " ".repeat(span.column.saturating_sub(1));
+    let carets = "^".repeat(len.max(1));
+
+    out.push_str(&format!("{gutter} {bar}\n"));
+    out.push_str(&format!("{} {bar} {}\n", number.blue().bold(), line));
+    out.push_str(&format!("{gutter} {bar} {}{}\n", padding, paint(&carets)));
+    out.push_str(&format!("{gutter} {bar}\n"));
+
+    out
+}
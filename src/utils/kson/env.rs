@@ -0,0 +1,68 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+
+use super::error::{KsonError, KsonErrorKind, Span};
+
+/// Where a document reads its env variables from: `env(...)`, `@env`,
+/// `${...}` and env overrides all look up the same variables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum EnvSource {
+    /// The process environment.
+    #[default]
+    Process,
+    /// `.env` files under the process environment, like `dotenv`: a variable set
+    /// in the process wins over the files, and a later file wins over an earlier one.
+    Files(Vec<String>),
+    /// Exactly these variables, e.g. for tests.
+    Map(HashMap<String, String>),
+    /// No variables at all.
+    None,
+}
+
+impl EnvSource {
+    /// Collect the variables of this source.
+    pub fn load(&self) -> Result<HashMap<String, String>, Vec<KsonError>> {
+        match self {
+            EnvSource::Process => Ok(env::vars().collect()),
+            EnvSource::Files(files) => {
+                let mut vars = HashMap::new();
+                let mut errors = vec![];
+
+                for file in files {
+                    match dotenvy::from_path_iter(file) {
+                        Ok(entries) => {
+                            for entry in entries {
+                                match entry {
+                                    Ok((name, value)) => {
+                                        vars.insert(name, value);
+                                    }
+                                    Err(e) => errors.push(unreadable(file, e)),
+                                }
+                            }
+                        }
+                        Err(e) => errors.push(unreadable(file, e)),
+                    }
+                }
+
+                if !errors.is_empty() {
+                    return Err(errors);
+                }
+
+                vars.extend(env::vars());
+                Ok(vars)
+            }
+            EnvSource::Map(vars) => Ok(vars.clone()),
+            EnvSource::None => Ok(HashMap::new()),
+        }
+    }
+}
+
+fn unreadable(file: &str, e: dotenvy::Error) -> KsonError {
+    KsonError::new(
+        KsonErrorKind::UnreadableFile(e.to_string()),
+        Span::new(0, 0),
+    )
+    .with_file(file)
+}
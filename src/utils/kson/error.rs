@@ -0,0 +1,354 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+/// Location of an item in a KSON or KModel source (1-based).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    /// Width of the item in characters, used to draw the caret under it.
+    pub len: usize,
+    /// File the item is in: 0 for the document being read, n for its n-th `@include`.
+    pub source: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize) -> Span {
+        Span {
+            line,
+            column,
+            len: 0,
+            source: 0,
+        }
+    }
+
+    /// Span of the first occurrence of `needle` on `line`.
+    pub fn find(line_no: usize, line: &str, needle: &str) -> Span {
+        let column = match line.find(needle) {
+            Some(byte) => line[..byte].chars().count() + 1,
+            None => 1,
+        };
+
+        Span::new(line_no, column).with_len(needle.chars().count())
+    }
+
+    pub fn with_len(mut self, len: usize) -> Span {
+        self.len = len;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KsonErrorKind {
+    /// The source file could not be opened or read.
+    UnreadableFile(String),
+    /// An `@env` variable is not set in the environment.
+    MissingEnv(String),
+    /// A `"""` string is never closed.
+    UnterminatedString(String),
+    /// A multi-line array or inline table is never closed.
+    Unclosed { key: String, delimiter: char },
+    /// The right-hand side of a property is not a valid KSON value.
+    InvalidValue { key: String, raw: String },
+    /// A bare `@env` name used as a value instead of `env(NAME)`.
+    BareEnv { key: String, name: String },
+    /// A value does not match the type declared in the KModel.
+    TypeMismatch {
+        key: String,
+        expected: String,
+        found: String,
+    },
+    /// The text of an env variable or secret cannot be converted to the type
+    /// declared in the KModel.
+    BadSourceValue {
+        key: String,
+        /// Where the text came from, e.g. `env var PORT`.
+        source: String,
+        /// The text, masked unless `--reveal` is given.
+        raw: String,
+        expected: String,
+    },
+    /// An array element does not match the element type declared in the KModel.
+    BadArrayType {
+        key: String,
+        /// Index path of the element, e.g. `[1][0]` for nested arrays.
+        index: String,
+        expected: String,
+        found: String,
+    },
+    /// A section marked as required in the KModel is absent.
+    MissingSection(String),
+    /// A property marked as required in the KModel is absent.
+    MissingProperty {
+        section: Option<String>,
+        key: String,
+    },
+    /// A `${name}` refers to neither a document path nor an env variable.
+    UnresolvedReference { key: String, name: String },
+    /// A `${name}` refers to a table, array or section.
+    NotInterpolable {
+        key: String,
+        name: String,
+        found: String,
+    },
+    /// `${...}` references that lead back to themselves, e.g. `a -> b -> a`.
+    ReferenceCycle(String),
+    /// A `@secret(...)` value could not be fetched.
+    SecretUnavailable {
+        key: String,
+        reference: String,
+        reason: String,
+    },
+    /// A value is not one of the values of its `Enum` type.
+    NotInEnum {
+        key: String,
+        found: String,
+        /// The allowed values, as written in the model.
+        allowed: String,
+        suggestion: Option<String>,
+    },
+    /// A value of the declared type breaks one of its constraints.
+    ConstraintViolation {
+        key: String,
+        constraint: String,
+        found: String,
+    },
+    /// A property or section the KModel does not declare, under the `strict` policy.
+    UnknownKey {
+        /// Dotted path of the key.
+        path: String,
+        section: bool,
+        /// The closest declared name at the same level.
+        suggestion: Option<String>,
+    },
+    /// A property the KModel marks `@secret` is written out in the document.
+    PlaintextSecret(String),
+    /// A property the KModel marks `@secret` has a default in the KModel.
+    SecretDefault(String),
+    /// An env override names a path that runs through a value or ends on a section.
+    BadOverride { name: String, path: String },
+    /// A dotted key runs through a property that is not a table, e.g. `a.b = 2`
+    /// after `a = 1`.
+    KeyConflict { key: String, prefix: String },
+    /// A file includes itself, directly or through other files.
+    IncludeCycle(String),
+    /// A `@` directive line is malformed.
+    InvalidDirective(String),
+    /// A KModel type declaration could not be parsed.
+    InvalidType(String),
+}
+
+impl fmt::Display for KsonErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KsonErrorKind::UnreadableFile(m) => write!(f, "cannot read file: {m}"),
+            KsonErrorKind::MissingEnv(name) => write!(f, "environment variable {name} is not set"),
+            KsonErrorKind::UnterminatedString(key) => {
+                write!(f, "unterminated multi-line string in property: {key}")
+            }
+            KsonErrorKind::Unclosed { key, delimiter } => {
+                write!(f, "unclosed `{delimiter}` in property: {key}")
+            }
+            KsonErrorKind::InvalidValue { key, raw } => {
+                write!(f, "invalid value for property: {key} = {raw}")
+            }
+            KsonErrorKind::BareEnv { key, name } => write!(
+                f,
+                "bare env var name in property: {key} = {name}, write env({name}) to read it or \"{name}\" for a string"
+            ),
+            KsonErrorKind::TypeMismatch {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "invalid value for property: {key} = {found}, expected value of type: {expected}"
+            ),
+            KsonErrorKind::BadSourceValue {
+                key,
+                source,
+                raw,
+                expected,
+            } => write!(
+                f,
+                "{source} = {raw:?} for property: {key} is not a valid value of type: {expected}"
+            ),
+            KsonErrorKind::BadArrayType {
+                key,
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "invalid value {found} at index{index} of property: {key}, expected value of type: {expected}"
+            ),
+            KsonErrorKind::NotInEnum {
+                key,
+                found,
+                allowed,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "invalid value for property: {key} = {found}, expected one of: {allowed}"
+                )?;
+
+                match suggestion {
+                    Some(suggestion) => write!(f, ", did you mean {suggestion}?"),
+                    None => Ok(()),
+                }
+            }
+            KsonErrorKind::ConstraintViolation {
+                key,
+                constraint,
+                found,
+            } => write!(
+                f,
+                "invalid value for property: {key} = {found}, does not satisfy constraint: {constraint}"
+            ),
+            KsonErrorKind::UnknownKey {
+                path,
+                section,
+                suggestion,
+            } => {
+                let what = if *section { "section" } else { "property" };
+                write!(f, "{what} {path} is not declared in KModel")?;
+
+                match suggestion {
+                    Some(suggestion) => write!(f, ", did you mean {suggestion}?"),
+                    None => Ok(()),
+                }
+            }
+            KsonErrorKind::PlaintextSecret(key) => write!(
+                f,
+                "secret property: {key} has a plaintext value, read it with env(NAME) or @secret(...)"
+            ),
+            KsonErrorKind::SecretDefault(key) => write!(
+                f,
+                "secret property: {key} has a default, which would be a plaintext secret in the KModel"
+            ),
+            KsonErrorKind::MissingSection(section) => {
+                write!(f, "section {section} is required by KModel")
+            }
+            KsonErrorKind::MissingProperty {
+                section: Some(section),
+                key,
+            } => write!(f, "property {key} in {section} is required by KModel"),
+            KsonErrorKind::MissingProperty { section: None, key } => {
+                write!(f, "property {key} is required by KModel")
+            }
+            KsonErrorKind::UnresolvedReference { key, name } => {
+                write!(f, "unresolved reference ${{{name}}} in property: {key}")
+            }
+            KsonErrorKind::NotInterpolable { key, name, found } => write!(
+                f,
+                "cannot interpolate ${{{name}}} of type {found} into property: {key}"
+            ),
+            KsonErrorKind::ReferenceCycle(chain) => write!(f, "reference cycle: {chain}"),
+            KsonErrorKind::SecretUnavailable {
+                key,
+                reference,
+                reason,
+            } => write!(
+                f,
+                "cannot fetch secret {reference} for property: {key}: {reason}"
+            ),
+            KsonErrorKind::BadOverride { name, path } => {
+                write!(f, "env override {name} does not name a value: {path}")
+            }
+            KsonErrorKind::KeyConflict { key, prefix } => {
+                write!(f, "property {key} conflicts with {prefix}, which is a value, not a table")
+            }
+            KsonErrorKind::IncludeCycle(chain) => write!(f, "include cycle: {chain}"),
+            KsonErrorKind::InvalidDirective(line) => write!(f, "invalid directive: {line}"),
+            KsonErrorKind::InvalidType(t) => write!(f, "invalid type: {t}"),
+        }
+    }
+}
+
+/// A secondary location attached to an error, e.g. the KModel declaration
+/// that set the expectation a value failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub file: Option<String>,
+    pub span: Span,
+    pub message: String,
+}
+
+/// An error raised while reading a KSON document or its KModel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KsonError {
+    /// Path of the offending file, or `None` for inline text.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    pub kind: Box<KsonErrorKind>,
+    pub note: Option<Box<Note>>,
+    /// `source` of the span the error was raised at.
+    pub source: usize,
+    /// The span holds a secret value, drawn as `***` in the snippet.
+    pub masked: bool,
+}
+
+impl KsonError {
+    pub fn new(kind: KsonErrorKind, span: Span) -> KsonError {
+        KsonError {
+            file: None,
+            line: span.line,
+            column: span.column,
+            len: span.len,
+            kind: Box::new(kind),
+            note: None,
+            source: span.source,
+            masked: false,
+        }
+    }
+
+    /// The span the error was raised at.
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            len: self.len,
+            source: self.source,
+        }
+    }
+
+    pub fn with_note(mut self, message: &str, file: Option<&str>, span: Span) -> KsonError {
+        self.note = Some(Box::new(Note {
+            file: file.map(|f| f.to_string()),
+            span,
+            message: message.to_string(),
+        }));
+        self
+    }
+
+    /// Draw the spanned text as `***` in the snippet when `masked`.
+    pub fn with_masked(mut self, masked: bool) -> KsonError {
+        self.masked = masked;
+        self
+    }
+
+    /// Attach `file` unless the error already points at another file (e.g. the KModel).
+    pub fn with_file(mut self, file: &str) -> KsonError {
+        if self.file.is_none() {
+            self.file = Some(file.to_string());
+        }
+        self
+    }
+}
+
+impl fmt::Display for KsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<text>");
+
+        // Line 0 means the error is about the file as a whole.
+        if self.line == 0 {
+            return write!(f, "{}: {}", file, self.kind);
+        }
+
+        write!(f, "{}:{}:{}: {}", file, self.line, self.column, self.kind)
+    }
+}
@@ -0,0 +1,240 @@
+#![allow(dead_code)]
+//! `@include("path")`: compose a document from other KSON files.
+//!
+//! Paths are relative to the including file (or to the working directory for
+//! `--text`). Included files are merged in order and the including file is
+//! merged last, so on conflicting keys the includer wins, then the later include.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::utils::lson::sha256_hex;
+
+use super::error::{KsonError, KsonErrorKind, Span};
+use super::{strip_comment, KSONItem, KsonValue, KSON};
+
+/// The files being read, to resolve relative paths and detect include cycles.
+pub(crate) struct Includes {
+    /// Canonical and displayed path of each file being parsed, outermost first.
+    stack: Vec<(PathBuf, String)>,
+    /// Every file included so far. A span with `source` n is in `files[n - 1]`.
+    pub files: Vec<String>,
+}
+
+impl Includes {
+    pub fn new(file: Option<&str>) -> Includes {
+        Includes {
+            stack: file
+                .map(|f| (canonical(f), f.to_string()))
+                .into_iter()
+                .collect(),
+            files: vec![],
+        }
+    }
+
+    /// Open `path`, included from `file`. Returns its resolved path and contents.
+    pub fn enter(
+        &mut self,
+        file: Option<&str>,
+        path: &str,
+    ) -> Result<(String, String), KsonErrorKind> {
+        let resolved = resolve(file, path);
+        let text = fs::read_to_string(&resolved)
+            .map_err(|e| KsonErrorKind::UnreadableFile(format!("{resolved}: {e}")))?;
+        let canonical = canonical(&resolved);
+
+        if let Some(position) = self.stack.iter().position(|(p, _)| *p == canonical) {
+            let mut chain: Vec<&str> = self.stack[position..]
+                .iter()
+                .map(|(_, f)| f.as_str())
+                .collect();
+            chain.push(&resolved);
+
+            return Err(KsonErrorKind::IncludeCycle(chain.join(" -> ")));
+        }
+
+        self.stack.push((canonical, resolved.clone()));
+
+        Ok((resolved, text))
+    }
+
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// The path named by an `@include("path")` or `@include(path)` line.
+pub(crate) fn directive(line: &str) -> Option<&str> {
+    let inner = line
+        .trim()
+        .strip_prefix("@include")?
+        .trim()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .trim();
+    let path = match inner.strip_prefix('"') {
+        Some(quoted) => quoted.strip_suffix('"')?,
+        None => inner,
+    };
+
+    (!path.is_empty()).then_some(path)
+}
+
+/// Mark the spans of a document read from an included file with its `source` index.
+pub(crate) fn retag(kson: &mut KSON, source: usize) {
+    retag_items(&mut kson.properties, source);
+    kson._templates
+        .iter_mut()
+        .for_each(|span| tag(span, source));
+    kson._text_sources
+        .iter_mut()
+        .for_each(|(span, _)| tag(span, source));
+    kson._sensitive
+        .iter_mut()
+        .for_each(|span| tag(span, source));
+    kson._placeholders
+        .iter_mut()
+        .for_each(|span| tag(span, source));
+    for (array, headers) in &mut kson._table_headers {
+        tag(array, source);
+        headers.iter_mut().for_each(|span| tag(span, source));
+    }
+}
+
+fn retag_items(items: &mut [KSONItem], source: usize) {
+    for item in items {
+        match item {
+            KSONItem::Section(_, children, span) => {
+                tag(span, source);
+                retag_items(children, source);
+            }
+            KSONItem::Property(_, value, span) => {
+                tag(span, source);
+                retag_value(value, source);
+            }
+        }
+    }
+}
+
+fn retag_value(value: &mut KsonValue, source: usize) {
+    match value {
+        KsonValue::Array(elements) => elements
+            .iter_mut()
+            .for_each(|element| retag_value(element, source)),
+        KsonValue::Table(children) => retag_items(children, source),
+        _ => {}
+    }
+}
+
+// Spans already tagged belong to files included from the included file.
+fn tag(span: &mut Span, source: usize) {
+    if span.source == 0 {
+        span.source = source;
+    }
+}
+
+/// Whether `text` has an `@include` line.
+pub fn uses_include(text: &str) -> bool {
+    text.lines()
+        .any(|line| strip_comment(line).trim_start().starts_with("@include"))
+}
+
+/// The contents of `path` and of every file it includes, depth first.
+pub fn source_files(path: &str) -> Result<Vec<String>, KsonError> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        KsonError::new(
+            KsonErrorKind::UnreadableFile(e.to_string()),
+            Span::new(0, 0),
+        )
+        .with_file(path)
+    })?;
+
+    let mut includes = Includes::new(Some(path));
+    let mut files = vec![];
+    collect(&text, path, &mut includes, &mut files)?;
+
+    Ok(files)
+}
+
+fn collect(
+    text: &str,
+    file: &str,
+    includes: &mut Includes,
+    files: &mut Vec<String>,
+) -> Result<(), KsonError> {
+    files.push(text.to_string());
+
+    for (index, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+
+        // Malformed directives are reported when the document is read.
+        let Some(path) = line
+            .starts_with("@include")
+            .then(|| directive(line))
+            .flatten()
+        else {
+            continue;
+        };
+
+        let (resolved, included) = includes.enter(Some(file), path).map_err(|kind| {
+            KsonError::new(kind, Span::find(index + 1, line, line.trim())).with_file(file)
+        })?;
+
+        collect(&included, &resolved, includes, files)?;
+        includes.leave();
+    }
+
+    Ok(())
+}
+
+/// SHA-256 of a KSON file, the files it includes and the `overlays` merged
+/// over it, used for `KSON-HASH` and lockfiles. A file without `@include` or
+/// overlays hashes to the SHA-256 of its bytes.
+pub fn source_hash(path: &str, overlays: &[String]) -> Result<String, KsonError> {
+    let mut files = source_files(path)?;
+
+    for overlay in overlays {
+        files.extend(source_files(overlay)?);
+    }
+
+    Ok(files_hash(&files))
+}
+
+/// [`source_hash`] of a document given as text.
+pub fn text_hash(text: &str, overlays: &[String]) -> Result<String, KsonError> {
+    let mut files = vec![text.to_string()];
+
+    for overlay in overlays {
+        files.extend(source_files(overlay)?);
+    }
+
+    Ok(files_hash(&files))
+}
+
+fn files_hash(files: &[String]) -> String {
+    if let [text] = files {
+        return sha256_hex(text.as_bytes());
+    }
+
+    let digests: Vec<String> = files
+        .iter()
+        .map(|text| sha256_hex(text.as_bytes()))
+        .collect();
+
+    sha256_hex(digests.join("\n").as_bytes())
+}
+
+fn resolve(file: Option<&str>, path: &str) -> String {
+    match file.and_then(|f| Path::new(f).parent()) {
+        Some(dir) if !Path::new(path).is_absolute() => {
+            dir.join(path).to_string_lossy().into_owned()
+        }
+        _ => path.to_string(),
+    }
+}
+
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
@@ -0,0 +1,420 @@
+#![allow(dead_code)]
+
+use crate::utils::debug::debug;
+use colored::Colorize;
+use std::collections::HashMap;
+
+use super::error::{KsonError, KsonErrorKind, Span};
+use super::redact::redact;
+use super::{env_value, walk, EnvVar, KSONItem, KsonValue, PathNode, KSON};
+
+/// Replace every `${name}` in the strings of the document.
+///
+/// `name` is first looked up as a dotted document path (`${server.host}`),
+/// then as an env variable. `$${` is a literal `${`. Only strings written
+/// with `${` in the source are expanded, so env values are taken verbatim.
+///
+/// A string that takes in an env variable or secret is sensitive itself.
+pub fn interpolate(
+    kson: &mut KSON,
+    env: &HashMap<String, String>,
+    verbose: bool,
+    reveal: bool,
+) -> Vec<KsonError> {
+    if kson._templates.is_empty() {
+        return vec![];
+    }
+
+    // References resolve against the document as written, not as it is being rewritten.
+    let document = kson.properties.clone();
+
+    let mut resolver = Resolver {
+        document: &document,
+        templates: &kson._templates,
+        env,
+        env_vars: &kson.env_vars,
+        sensitive: &kson._sensitive,
+        verbose,
+        reveal,
+        stack: vec![],
+        cyclic: vec![],
+        tainted: false,
+        tainted_spans: vec![],
+        errors: vec![],
+    };
+
+    resolver.visit(&mut kson.properties, "");
+
+    let Resolver {
+        tainted_spans,
+        errors,
+        ..
+    } = resolver;
+    kson._sensitive.extend(tainted_spans);
+
+    errors
+}
+
+struct Resolver<'a> {
+    document: &'a Vec<KSONItem>,
+    templates: &'a [Span],
+    env: &'a HashMap<String, String>,
+    env_vars: &'a [EnvVar],
+    sensitive: &'a [Span],
+    verbose: bool,
+    reveal: bool,
+    /// Dotted paths of the strings being expanded, outermost first, with the
+    /// span of their property. A path met again closes a cycle.
+    stack: Vec<(Span, String)>,
+    /// Paths already reported as part of a cycle.
+    cyclic: Vec<String>,
+    /// Whether the string being expanded took in a sensitive value.
+    tainted: bool,
+    /// Templates that took in a sensitive value.
+    tainted_spans: Vec<Span>,
+    errors: Vec<KsonError>,
+}
+
+impl Resolver<'_> {
+    fn error(&mut self, kind: KsonErrorKind) {
+        let span = self.stack.last().map(|(span, _)| *span).unwrap_or_default();
+        let error = KsonError::new(kind, span);
+
+        // A property expanded through a reference reports the same error again.
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    fn visit(&mut self, items: &mut [KSONItem], prefix: &str) {
+        for item in items {
+            match item {
+                KSONItem::Section(name, children, _) => {
+                    self.visit(children, &join(prefix, name));
+                }
+                KSONItem::Property(key, value, span) => {
+                    let path = join(prefix, key);
+
+                    if self.templates.contains(span) {
+                        self.tainted = false;
+                        self.visit_value(value, &path, Some(*span));
+
+                        if self.tainted {
+                            self.tainted_spans.push(*span);
+                        }
+                    } else {
+                        self.visit_value(value, &path, None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// `template` is the span of the enclosing property, if its strings are expanded.
+    fn visit_value(&mut self, value: &mut KsonValue, path: &str, template: Option<Span>) {
+        match value {
+            KsonValue::String(s) => {
+                if let Some(span) = template {
+                    self.stack.push((span, path.to_string()));
+                    if let Some(expanded) = self.expand(s) {
+                        *s = expanded;
+                    }
+                    self.stack.pop();
+                }
+            }
+            KsonValue::Array(elements) => {
+                for (index, element) in elements.iter_mut().enumerate() {
+                    self.visit_value(element, &format!("{path}.{index}"), template);
+                }
+            }
+            KsonValue::Table(items) => self.visit(items, path),
+            _ => {}
+        }
+    }
+
+    /// Expand the `${...}` references in `s`.
+    fn expand(&mut self, s: &str) -> Option<String> {
+        let key = self
+            .stack
+            .last()
+            .map(|(_, path)| path.clone())
+            .unwrap_or_default();
+        let mut out = String::new();
+        let mut rest = s;
+        let mut ok = true;
+
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if let Some(after) = rest.strip_prefix("$${") {
+                out.push_str("${");
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("${") {
+                let Some(end) = after.find('}') else {
+                    self.error(KsonErrorKind::Unclosed {
+                        key,
+                        delimiter: '{',
+                    });
+                    return None;
+                };
+
+                match self.reference(after[..end].trim(), &key) {
+                    Some(value) => out.push_str(&value),
+                    None => ok = false,
+                }
+
+                rest = &after[end + 1..];
+            } else {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+
+        out.push_str(rest);
+
+        ok.then_some(out)
+    }
+
+    /// The text `${name}` stands for, inside the property `key`.
+    fn reference(&mut self, name: &str, key: &str) -> Option<String> {
+        let Some((node, owner)) = walk(self.document, name) else {
+            return self.env(name, key);
+        };
+
+        let (value, sensitive) = match node {
+            PathNode::Value(KsonValue::String(s)) if self.templates.contains(&owner) => {
+                if let Some(position) = self.stack.iter().position(|(_, path)| path == name) {
+                    self.cycle(position, name);
+                    return None;
+                }
+
+                let outer = std::mem::replace(&mut self.tainted, false);
+                self.stack.push((owner, name.to_string()));
+                let expanded = self.expand(s);
+                self.stack.pop();
+                let sensitive = std::mem::replace(&mut self.tainted, outer);
+
+                (expanded?, sensitive)
+            }
+            PathNode::Value(value) => (
+                self.text(value, name, key)?,
+                self.sensitive.contains(&owner),
+            ),
+            PathNode::Items(_) => {
+                self.not_interpolable(name, key, "Section".to_string());
+                return None;
+            }
+        };
+
+        self.tainted |= sensitive;
+
+        debug(
+            self.verbose,
+            &format!(
+                "{}: ${{{}}} = {}",
+                "Interpolating".yellow(),
+                name.bold().black(),
+                redact(&value, self.reveal || !sensitive).red()
+            ),
+        );
+
+        Some(value)
+    }
+
+    fn env(&mut self, name: &str, key: &str) -> Option<String> {
+        let Some(value) = env_value(self.env, self.env_vars, name) else {
+            return match self.env_vars.iter().find(|var| var.name == name) {
+                Some(var) if var.optional => Some(String::new()),
+                // A declared variable that is unset was already reported on its `@env` line.
+                Some(_) => None,
+                None => {
+                    self.error(KsonErrorKind::UnresolvedReference {
+                        key: key.to_string(),
+                        name: name.to_string(),
+                    });
+                    None
+                }
+            };
+        };
+
+        let value = self.text(&value, name, key)?;
+        self.tainted = true;
+
+        debug(
+            self.verbose,
+            &format!(
+                "{}: ${{{}}} = {}",
+                "Interpolating env var".yellow(),
+                name.bold().black(),
+                redact(&value, self.reveal).red()
+            ),
+        );
+
+        Some(value)
+    }
+
+    /// `value` as it reads inside a string.
+    fn text(&mut self, value: &KsonValue, name: &str, key: &str) -> Option<String> {
+        match value {
+            KsonValue::String(s) => Some(s.clone()),
+            KsonValue::Char(c) => Some(c.to_string()),
+            KsonValue::Integer(_) | KsonValue::Float(_) | KsonValue::Bool(_) | KsonValue::Null => {
+                Some(value.to_json())
+            }
+            KsonValue::Array(_) | KsonValue::Table(_) => {
+                self.not_interpolable(name, key, value.type_name().to_string());
+                None
+            }
+        }
+    }
+
+    fn not_interpolable(&mut self, name: &str, key: &str, found: String) {
+        self.error(KsonErrorKind::NotInterpolable {
+            key: key.to_string(),
+            name: name.to_string(),
+            found,
+        });
+    }
+
+    /// Report the cycle that starts at `stack[position]` and is closed by `name`.
+    fn cycle(&mut self, position: usize, name: &str) {
+        let (start, ref path) = self.stack[position];
+
+        if self.cyclic.contains(path) {
+            return;
+        }
+
+        let mut chain: Vec<&str> = self.stack[position..]
+            .iter()
+            .map(|(_, path)| path.as_str())
+            .collect();
+        chain.push(name);
+
+        let kind = KsonErrorKind::ReferenceCycle(chain.join(" -> "));
+        self.errors.push(KsonError::new(kind, start));
+        self.cyclic
+            .extend(self.stack[position..].iter().map(|(_, path)| path.clone()));
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read, EnvSource, ReadOptions};
+    use super::*;
+
+    fn read_with(text: &str, env: &[(&str, &str)]) -> Result<KSON, Vec<KsonError>> {
+        let options = ReadOptions {
+            env: EnvSource::Map(
+                env.iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            ..ReadOptions::default()
+        };
+
+        read(text, &options)
+    }
+
+    fn string(kson: &KSON, path: &str) -> String {
+        match kson.get_path(path) {
+            Some(KsonValue::String(s)) => s.clone(),
+            other => panic!("{path} is not a string: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn expands_document_paths() {
+        let kson = read_with(
+            "host = \"db\"\nport = 5432\n$server\n    url = \"pg://${host}:${port}/${server.name}\"\n    name = \"app\"\n",
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(string(&kson, "server.url"), "pg://db:5432/app");
+    }
+
+    #[test]
+    fn falls_back_to_env_variables() {
+        let kson = read_with("home = \"${HOME}/app\"", &[("HOME", "/root")]).unwrap();
+
+        assert_eq!(string(&kson, "home"), "/root/app");
+    }
+
+    #[test]
+    fn expands_references_to_templates() {
+        let kson = read_with("a = \"${b}!\"\nb = \"${c}${c}\"\nc = \"x\"", &[]).unwrap();
+
+        assert_eq!(string(&kson, "a"), "xx!");
+    }
+
+    #[test]
+    fn escapes_dollar_brace() {
+        let kson = read_with(
+            "literal = \"$${HOME}\"\nmixed = \"$${a} ${a}\"\na = \"1\"",
+            &[("HOME", "/root")],
+        )
+        .unwrap();
+
+        assert_eq!(string(&kson, "literal"), "${HOME}");
+        assert_eq!(string(&kson, "mixed"), "${a} 1");
+    }
+
+    #[test]
+    fn expands_inside_arrays_and_inline_tables() {
+        let kson = read_with(
+            "v = \"1\"\nlist = [\"${v}\", \"b\"]\ntable = { url = \"${v}.0\" }",
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(
+            kson.get_path("list"),
+            Some(&KsonValue::Array(vec![
+                KsonValue::String("1".to_string()),
+                KsonValue::String("b".to_string()),
+            ]))
+        );
+        assert_eq!(string(&kson, "table.url"), "1.0");
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let errors = read_with("a = \"${b}\"\nb = \"${a}\"", &[]).err().unwrap();
+
+        assert!(!errors.is_empty());
+        assert!(errors
+            .iter()
+            .all(|error| matches!(*error.kind, KsonErrorKind::ReferenceCycle(_))));
+    }
+
+    #[test]
+    fn reports_self_references() {
+        let errors = read_with("a = \"${a}\"", &[]).err().unwrap();
+
+        assert!(matches!(*errors[0].kind, KsonErrorKind::ReferenceCycle(_)));
+    }
+
+    #[test]
+    fn reports_unresolved_references_and_tables() {
+        let errors = read_with("a = \"${nope}\"", &[]).err().unwrap();
+        assert!(matches!(
+            *errors[0].kind,
+            KsonErrorKind::UnresolvedReference { .. }
+        ));
+
+        let errors = read_with("t = { x = 1 }\na = \"${t}\"", &[]).err().unwrap();
+        assert!(matches!(
+            *errors[0].kind,
+            KsonErrorKind::NotInterpolable { .. }
+        ));
+    }
+}
@@ -1,15 +1,15 @@
 #![allow(dead_code)]
 
 use colored::Colorize;
-use regex::Regex;
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    process::exit,
-};
+use std::fs;
 
 use crate::utils::debug::debug;
 
+use super::constraint::{self, Constraint};
+use super::error::{KsonError, KsonErrorKind, Span};
+use super::strip_comment_quoted;
+use super::value::KsonValue;
+
 #[derive(Debug)]
 pub enum KType {
     Any,
@@ -20,16 +20,128 @@ pub enum KType {
     Float,
     Boolean,
     Array(Box<KType>),
+    /// `Enum<"local", "ldap">` or `Enum<1, 2, 3>`: one of a fixed set of strings or integers.
+    Enum(Vec<KsonValue>),
     Optional(Box<KType>),
+    /// `@secret`: the value must come from an env variable or secret provider,
+    /// and is masked in output.
+    Secret(Box<KType>),
+    /// `Integer(1..=65535)`: the type with constraints on its values, see [`constraint`].
+    Constrained(Box<KType>, Vec<Constraint>),
+    /// `Integer = 20`: the value used when the document leaves the property out.
+    Defaulted(Box<KType>, KsonValue),
 }
 
 impl KType {
     pub fn is_required(&self) -> bool {
         match self {
-            KType::Optional(_) => false,
+            KType::Optional(_) | KType::Defaulted(..) => false,
+            KType::Secret(k) | KType::Constrained(k, _) => k.is_required(),
             _ => true,
         }
     }
+
+    pub fn is_secret(&self) -> bool {
+        match self {
+            KType::Secret(_) => true,
+            KType::Defaulted(k, _) => k.is_secret(),
+            _ => false,
+        }
+    }
+
+    /// The value declared with `= value`, if any.
+    pub fn default(&self) -> Option<&KsonValue> {
+        match self {
+            KType::Defaulted(_, default) => Some(default),
+            _ => None,
+        }
+    }
+
+    /// The type without its `?` and `@secret` markers and constraints.
+    pub fn inner(&self) -> &KType {
+        match self {
+            KType::Optional(k)
+            | KType::Secret(k)
+            | KType::Constrained(k, _)
+            | KType::Defaulted(k, _) => k.inner(),
+            k => k,
+        }
+    }
+
+    /// The constraints values of this type must satisfy.
+    pub fn constraints(&self) -> &[Constraint] {
+        match self {
+            KType::Optional(k) | KType::Secret(k) | KType::Defaulted(k, _) => k.constraints(),
+            KType::Constrained(_, constraints) => constraints,
+            _ => &[],
+        }
+    }
+
+    /// Whether `value` is a valid instance of this type.
+    pub fn accepts(&self, value: &KsonValue) -> bool {
+        match (self, value) {
+            (KType::Any, _) => true,
+            (KType::Optional(_), KsonValue::Null) => true,
+            (KType::Optional(k), v) => k.accepts(v),
+            (KType::Secret(k) | KType::Constrained(k, _) | KType::Defaulted(k, _), v) => {
+                k.accepts(v)
+            }
+            (KType::String, KsonValue::String(_)) => true,
+            (KType::Char, KsonValue::Char(_)) => true,
+            (KType::Integer, KsonValue::Integer(_)) => true,
+            // Integers are valid floats, as they were before values were typed.
+            (KType::Float, KsonValue::Float(_) | KsonValue::Integer(_)) => true,
+            (KType::Boolean, KsonValue::Bool(_)) => true,
+            (KType::Enum(allowed), v) => allowed.contains(v),
+            (KType::Array(kind), KsonValue::Array(elements)) => {
+                elements.iter().all(|e| kind.accepts(e))
+            }
+            _ => false,
+        }
+    }
+
+    /// Convert the text of an env variable to this type. Arrays are
+    /// comma-separated, e.g. `HOSTS=db-1,db-2`.
+    pub fn coerce(&self, raw: &str) -> Option<KsonValue> {
+        let trimmed = raw.trim();
+
+        match self {
+            KType::Optional(_) if trimmed.is_empty() => Some(KsonValue::Null),
+            KType::Optional(k)
+            | KType::Secret(k)
+            | KType::Constrained(k, _)
+            | KType::Defaulted(k, _) => k.coerce(raw),
+            KType::Any | KType::Unknown | KType::String => Some(KsonValue::String(raw.to_string())),
+            KType::Char => {
+                let mut chars = raw.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(KsonValue::Char(c)),
+                    _ => None,
+                }
+            }
+            KType::Integer => trimmed.parse().ok().map(KsonValue::Integer),
+            KType::Float => match KsonValue::parse(trimmed, Default::default())? {
+                KsonValue::Integer(i) => Some(KsonValue::Float(i as f64)),
+                value @ KsonValue::Float(_) => Some(value),
+                _ => None,
+            },
+            KType::Boolean => match trimmed.to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" | "on" => Some(KsonValue::Bool(true)),
+                "false" | "0" | "no" | "off" => Some(KsonValue::Bool(false)),
+                _ => None,
+            },
+            KType::Enum(allowed) => match allowed.first() {
+                Some(KsonValue::Integer(_)) => trimmed.parse().ok().map(KsonValue::Integer),
+                _ => Some(KsonValue::String(raw.to_string())),
+            },
+            KType::Array(_) if trimmed.is_empty() => Some(KsonValue::Array(vec![])),
+            KType::Array(kind) => raw
+                .split(',')
+                .map(|element| kind.coerce(element.trim()))
+                .collect::<Option<Vec<_>>>()
+                .map(KsonValue::Array),
+        }
+    }
 }
 
 impl ToString for KType {
@@ -43,7 +155,14 @@ impl ToString for KType {
             KType::Any => "Any".to_string(),
             KType::Unknown => "Unknown".to_string(),
             KType::Array(kind) => format!("Array<{}>", kind.to_string()),
+            KType::Enum(allowed) => format!("Enum<{}>", enum_values(allowed)),
             KType::Optional(k) => format!("{}?", k.to_string()),
+            KType::Secret(k) => format!("{} @secret", k.to_string()),
+            KType::Defaulted(k, default) => format!("{} = {}", k.to_string(), default),
+            KType::Constrained(k, constraints) => {
+                let constraints: Vec<String> = constraints.iter().map(|c| c.to_string()).collect();
+                format!("{}({})", k.to_string(), constraints.join(", "))
+            }
         }
     }
 }
@@ -72,16 +191,45 @@ impl ToString for KModelSection {
     }
 }
 
+/// What validation does with properties and sections the model does not declare.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnknownKeys {
+    /// Report them as errors, suggesting the closest declared name.
+    Strict,
+    /// Keep them and print a warning.
+    #[default]
+    Warn,
+    /// Keep them silently.
+    Allow,
+}
+
+impl UnknownKeys {
+    pub fn parse(policy: &str) -> Option<UnknownKeys> {
+        match policy {
+            "strict" => Some(UnknownKeys::Strict),
+            "warn" => Some(UnknownKeys::Warn),
+            "allow" => Some(UnknownKeys::Allow),
+            _ => None,
+        }
+    }
+}
+
+/// A KModel declaration. The span points at the declaring line in the `.kmodel` file.
 #[derive(Debug)]
 pub enum KItemType {
-    Section(KModelSection, Vec<KItemType>),
-    Property(String, KType),
+    Section(KModelSection, Vec<KItemType>, Span),
+    Property(String, KType, Span),
 }
 
 #[derive(Debug)]
 pub struct KModel {
     pub properties: Vec<KItemType>,
     pub _sections: Vec<String>,
+    pub _section_indents: Vec<usize>, // Track indentation level for each section
+    /// Path of the `.kmodel` file the model was read from.
+    pub file: Option<String>,
+    /// `Strict` when the model has an `@strict` line.
+    pub unknown_keys: Option<UnknownKeys>,
 }
 
 impl KModel {
@@ -89,14 +237,17 @@ impl KModel {
         KModel {
             properties,
             _sections: vec![],
+            _section_indents: vec![],
+            file: None,
+            unknown_keys: None,
         }
     }
 
-    pub fn get_property(&self, key: &str) -> Option<&KType> {
+    pub fn get_property(&self, key: &str) -> Option<(&KType, Span)> {
         for item in &self.properties {
-            if let KItemType::Property(k, v) = item {
+            if let KItemType::Property(k, v, span) = item {
                 if k == key {
-                    return Some(v);
+                    return Some((v, *span));
                 }
             }
         }
@@ -104,32 +255,28 @@ impl KModel {
         None
     }
 
+    /// A section, addressed by its dotted path such as `database.replica`.
     pub fn get_section(&self, section: &str) -> Option<&Vec<KItemType>> {
-        for item in &self.properties {
-            if let KItemType::Section(k, v) = item {
-                let k = k.to_string();
+        let mut items = &self.properties;
 
-                if k == section {
-                    return Some(v);
-                }
-            }
+        for name in section.split('.') {
+            items = items.iter().find_map(|item| match item {
+                KItemType::Section(k, children, _) if k.to_string() == name => Some(children),
+                _ => None,
+            })?;
         }
 
-        None
+        Some(items)
     }
 
-    pub fn get_section_property(&self, section: &str, key: &str) -> Option<&KType> {
-        if let Some(section) = self.get_section(section) {
-            for item in section {
-                if let KItemType::Property(k, v) = item {
-                    if k == key {
-                        return Some(v);
-                    }
-                }
-            }
-        }
-
-        None
+    /// The declaration of `key` in the section at the dotted path `section`.
+    pub fn get_section_property(&self, section: &str, key: &str) -> Option<(&KType, Span)> {
+        self.get_section(section)?
+            .iter()
+            .find_map(|item| match item {
+                KItemType::Property(k, v, span) if k == key => Some((v, *span)),
+                _ => None,
+            })
     }
 
     pub fn last_section(&self) -> Option<&str> {
@@ -140,23 +287,47 @@ impl KModel {
         }
     }
 
-    pub fn push_section(&mut self, section: KModelSection) {
-        self.properties
-            .push(KItemType::Section(section.clone(), vec![]));
-        self._sections.push(section.to_string());
+    /// Open `section` inside the innermost open section.
+    pub fn push_section(&mut self, section: KModelSection, indent: usize, span: Span) {
+        let name = section.to_string();
+        self.container()
+            .push(KItemType::Section(section, vec![], span));
+        self._sections.push(name);
+        self._section_indents.push(indent);
     }
 
     pub fn pop_section(&mut self) {
         self._sections.pop();
+        self._section_indents.pop();
     }
 
     pub fn attr(&mut self, item: KItemType) {
-        if let Some(_section) = self.last_section() {
-            if let Some(KItemType::Section(_section, properties)) = self.properties.last_mut() {
-                properties.push(item);
+        self.container().push(item);
+    }
+
+    /// The list new declarations go into: the innermost open section.
+    fn container(&mut self) -> &mut Vec<KItemType> {
+        let mut items = &mut self.properties;
+
+        for name in &self._sections {
+            let position = items.iter().rposition(
+                |item| matches!(item, KItemType::Section(k, _, _) if k.to_string() == *name),
+            );
+
+            match position {
+                Some(position) => items = Self::children_mut(&mut items[position]).unwrap(),
+                // If we reach here, something went wrong
+                None => break,
             }
-        } else {
-            self.properties.push(item);
+        }
+
+        items
+    }
+
+    fn children_mut(item: &mut KItemType) -> Option<&mut Vec<KItemType>> {
+        match item {
+            KItemType::Section(_, children, _) => Some(children),
+            KItemType::Property(..) => None,
         }
     }
 }
@@ -169,21 +340,69 @@ pub fn get_kmodel_colored() -> String {
     kmodel_string
 }
 
-pub fn read(file_path: &str, verbose: bool) -> KModel {
-    // Open the file
-    let file = File::open(file_path).expect("MISSING_KMODEL".on_bright_red().to_string().as_str());
+pub fn read(file_path: &str, verbose: bool) -> Result<KModel, KsonError> {
+    let text = fs::read_to_string(file_path).map_err(|e| {
+        KsonError::new(
+            KsonErrorKind::UnreadableFile(e.to_string()),
+            Span::new(0, 0),
+        )
+        .with_file(file_path)
+    })?;
 
-    // Read the file line by line
-    let reader = BufReader::new(file);
     let mut kson = KModel::new(vec![]);
+    kson.file = Some(file_path.to_string());
 
     let kmodel_string = get_kmodel_colored();
 
-    for line in reader.lines() {
-        let line = line.expect("Error reading line");
+    for (index, line) in text.lines().enumerate() {
+        let line_no = index + 1;
+        // A `#` inside a `/pattern/` constraint is part of the pattern.
+        let line = strip_comment_quoted(line, &['"', '\'', '/']);
+        let invalid_type = |value: &str| {
+            KsonError::new(
+                KsonErrorKind::InvalidType(value.to_string()),
+                Span::find(line_no, line, value),
+            )
+            .with_file(file_path)
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line.trim() == "@strict" {
+            debug(
+                verbose,
+                &format!("{} Undeclared keys are errors", kmodel_string),
+            );
+            kson.unknown_keys = Some(UnknownKeys::Strict);
+            continue;
+        }
+
+        // Sections nest by indentation, of any width, like in KSON.
+        let leading_whitespace = line.len() - line.trim_start().len();
+
+        while let Some(&last_indent) = kson._section_indents.last() {
+            if leading_whitespace > last_indent {
+                break;
+            }
 
-        if line.starts_with("$") {
-            let section = line[1..].trim();
+            debug(
+                verbose,
+                &format!(
+                    "{} Exiting from section: {}",
+                    kmodel_string,
+                    kson.last_section().unwrap().bold().bright_red()
+                ),
+            );
+            kson.pop_section();
+        }
+
+        let trimmed_line = line.trim_start();
+
+        if trimmed_line.starts_with("$") {
+            // `$$name` describes each table of a KSON array of tables.
+            let section = trimmed_line.trim_start_matches('$').trim();
             debug(
                 verbose,
                 &format!(
@@ -193,36 +412,22 @@ pub fn read(file_path: &str, verbose: bool) -> KModel {
                 ),
             );
 
+            let span = Span::find(line_no, &line, section);
+
             if section.ends_with("?") {
-                kson.push_section(KModelSection::Optional(
-                    section[..section.len() - 1].to_string(),
-                ));
+                kson.push_section(
+                    KModelSection::Optional(section[..section.len() - 1].to_string()),
+                    leading_whitespace,
+                    span,
+                );
             } else {
-                kson.push_section(KModelSection::Required(section.to_string()));
-            }
-        } else if let Some((key, value)) = parse_property_line(&line) {
-            if !kson._sections.is_empty() {
-                if !line.starts_with("   ".repeat(kson._sections.len()).as_str()) {
-                    debug(
-                        verbose,
-                        &format!(
-                            "{} Exiting from section: {}",
-                            kmodel_string,
-                            kson.last_section().unwrap().bold().bright_red()
-                        ),
-                    );
-                    kson.pop_section();
-                }
-            }
-
-            if line.starts_with(&key) && !kson._sections.is_empty() {
-                debug(
-                    verbose,
-                    &format!("{} Exiting from all sections", kmodel_string),
+                kson.push_section(
+                    KModelSection::Required(section.to_string()),
+                    leading_whitespace,
+                    span,
                 );
-                kson._sections.clear();
             }
-
+        } else if let Some((key, value)) = parse_property_line(&line) {
             debug(
                 verbose,
                 &format!(
@@ -233,58 +438,197 @@ pub fn read(file_path: &str, verbose: bool) -> KModel {
                 ),
             );
 
-            if value.ends_with("?") {
-                kson.attr(KItemType::Property(
-                    key,
-                    KType::Optional(Box::new(parse_type(&value[..value.len() - 1]))),
-                ));
+            let (value, default) = match split_default(&value) {
+                Some((value, default)) => (value.to_string(), Some(default.to_string())),
+                None => (value, None),
+            };
+
+            let (value, secret) = match value.strip_suffix("@secret") {
+                Some(value) => (value.trim_end().to_string(), true),
+                None => (value, false),
+            };
+
+            let ktype = if value.ends_with("?") {
+                KType::Optional(Box::new(
+                    parse_type(&value[..value.len() - 1]).ok_or_else(|| invalid_type(&value))?,
+                ))
             } else {
-                kson.attr(KItemType::Property(key, parse_type(&value)));
-            }
+                parse_type(&value).ok_or_else(|| invalid_type(&value))?
+            };
+            let ktype = if secret {
+                KType::Secret(Box::new(ktype))
+            } else {
+                ktype
+            };
+            let ktype = match default {
+                Some(raw) => {
+                    let default =
+                        parse_default(&key, &raw, &ktype, Span::find(line_no, line, &raw))
+                            .map_err(|e| e.with_file(file_path))?;
+                    KType::Defaulted(Box::new(ktype), default)
+                }
+                None => ktype,
+            };
+
+            let span = Span::find(line_no, &line, line.trim());
+            kson.attr(KItemType::Property(key, ktype, span));
         }
     }
 
-    kson
+    Ok(kson)
 }
 
-fn parse_type(t: &str) -> KType {
-    match t {
+/// Parse a type declaration such as `Integer`, `Array<Array<String>>` or
+/// `Integer(1..=65535)`. Returns `None` for a malformed `Array` type or
+/// constraint, a constraint that does not fit the type, or a type name with
+/// stray brackets. Other unknown names are [`KType::Unknown`].
+fn parse_type(t: &str) -> Option<KType> {
+    if let Some((base, constraints)) = split_constraints(t) {
+        let ktype = parse_type(base)?;
+        let constraints = constraint::parse_list(constraints)?;
+        let name = match &ktype {
+            KType::Array(_) => "Array".to_string(),
+            k => k.to_string(),
+        };
+
+        if !constraints.iter().all(|c| c.applies_to(&name)) {
+            return None;
+        }
+
+        return Some(KType::Constrained(Box::new(ktype), constraints));
+    }
+
+    let ktype = match t {
         "String" => KType::String,
         "Char" => KType::Char,
         "Integer" => KType::Integer,
         "Float" => KType::Float,
         "Bool" => KType::Boolean,
         "Any" => KType::Any,
-        k if k.starts_with("Array") => {
-            if k.contains("<") && k.contains(">") {
-                let re = Regex::new(r"<([^<>]+)>").unwrap();
-                let kind = re.captures(k);
+        k if k.starts_with("Enum") => {
+            let values = k.strip_prefix("Enum<")?.strip_suffix('>')?;
 
-                if kind.is_some() {
-                    let (_, [kind]) = kind.unwrap().extract();
+            // The values read like the elements of a KSON array.
+            let KsonValue::Array(allowed) =
+                KsonValue::parse(&format!("[{values}]"), Span::default())?
+            else {
+                return None;
+            };
 
-                    if kind.ends_with("?") {
-                        return KType::Array(Box::new(KType::Optional(Box::new(parse_type(
-                            &kind[..kind.len() - 1],
-                        )))));
-                    }
+            let strings = allowed.iter().all(|v| matches!(v, KsonValue::String(_)));
+            let integers = allowed.iter().all(|v| matches!(v, KsonValue::Integer(_)));
 
-                    return KType::Array(Box::new(parse_type(kind)));
-                }
+            if allowed.is_empty() || !(strings || integers) {
+                return None;
             }
 
-            eprintln!("Invalid array type received");
-            exit(1);
+            KType::Enum(allowed)
+        }
+        k if k.starts_with("Array") => {
+            let kind = k.strip_prefix("Array<")?.strip_suffix('>')?.trim();
+
+            match kind.strip_suffix('?') {
+                Some(kind) => KType::Array(Box::new(KType::Optional(Box::new(parse_type(kind)?)))),
+                None => KType::Array(Box::new(parse_type(kind)?)),
+            }
+        }
+        k if k.chars().all(|c| c.is_alphanumeric() || c == '_') => KType::Unknown,
+        _ => return None,
+    };
+
+    Some(ktype)
+}
+
+/// Split `Integer(1..100) = 20` into the type and its default, at the first
+/// `=` outside brackets and strings.
+fn split_default(t: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    let mut quoted = false;
+
+    for (i, c) in t.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '(' | '<' | '[' | '{' => depth += 1,
+            ')' | '>' | ']' | '}' => depth -= 1,
+            '=' if depth == 0 => return Some((t[..i].trim(), t[i + 1..].trim())),
+            _ => {}
         }
-        _ => KType::Unknown,
     }
+
+    None
 }
 
-fn parse_property_line(line: &str) -> Option<(String, String)> {
-    let parts: Vec<&str> = line.split(':').map(|s| s.trim()).collect();
-    if parts.len() == 2 {
-        Some((parts[0].to_string(), parts[1].to_string()))
-    } else {
-        None
+/// Parse the default `raw` of `key` and check it against `ktype`.
+fn parse_default(key: &str, raw: &str, ktype: &KType, span: Span) -> Result<KsonValue, KsonError> {
+    // Filled-in defaults are written to lockfiles and JSON output as they are.
+    if ktype.is_secret() {
+        return Err(
+            KsonError::new(KsonErrorKind::SecretDefault(key.to_string()), span).with_masked(true),
+        );
     }
+
+    let default = KsonValue::parse(raw, span).ok_or_else(|| {
+        KsonError::new(
+            KsonErrorKind::InvalidValue {
+                key: key.to_string(),
+                raw: raw.to_string(),
+            },
+            span,
+        )
+    })?;
+
+    if !ktype.accepts(&default) {
+        return Err(KsonError::new(
+            KsonErrorKind::TypeMismatch {
+                key: key.to_string(),
+                expected: ktype.inner().to_string(),
+                found: default.to_string(),
+            },
+            span,
+        ));
+    }
+
+    if let Some(constraint) = ktype.constraints().iter().find(|c| !c.allows(&default)) {
+        return Err(KsonError::new(
+            KsonErrorKind::ConstraintViolation {
+                key: key.to_string(),
+                constraint: constraint.to_string(),
+                found: default.to_string(),
+            },
+            span,
+        ));
+    }
+
+    Ok(default)
+}
+
+/// The values of an `Enum`, as written in the model.
+pub fn enum_values(allowed: &[KsonValue]) -> String {
+    let values: Vec<String> = allowed.iter().map(|v| v.to_string()).collect();
+    values.join(", ")
+}
+
+/// Split `Integer(1..=65535)` into the type and the text of its constraints.
+fn split_constraints(t: &str) -> Option<(&str, &str)> {
+    let t = t.strip_suffix(')')?;
+    let mut depth = 0;
+
+    // The first `(` outside `Array<...>` opens the constraints.
+    for (i, c) in t.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            '(' if depth == 0 => return Some((t[..i].trim(), &t[i + 1..])),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn parse_property_line(line: &str) -> Option<(String, String)> {
+    // Only the first `:` ends the key; patterns may contain more.
+    let (key, value) = line.split_once(':')?;
+    Some((key.trim().to_string(), value.trim().to_string()))
 }
@@ -0,0 +1,135 @@
+#![allow(dead_code)]
+
+use super::{KSONItem, KsonValue};
+
+/// Deep-merge `overlay` into `base`: sections and tables with the same name
+/// are merged key by key, any other value in `overlay` replaces the one in `base`.
+pub fn merge(base: &mut Vec<KSONItem>, overlay: Vec<KSONItem>) {
+    for item in overlay {
+        let name = match &item {
+            KSONItem::Section(name, _, _) | KSONItem::Property(name, _, _) => name.clone(),
+        };
+
+        let existing = base.iter_mut().find(|existing| match existing {
+            KSONItem::Section(k, _, _) | KSONItem::Property(k, _, _) => *k == name,
+        });
+
+        match (existing, item) {
+            (Some(KSONItem::Section(_, children, _)), KSONItem::Section(_, overlay, _))
+            | (
+                Some(KSONItem::Section(_, children, _)),
+                KSONItem::Property(_, KsonValue::Table(overlay), _),
+            )
+            | (
+                Some(KSONItem::Property(_, KsonValue::Table(children), _)),
+                KSONItem::Section(_, overlay, _),
+            )
+            | (
+                Some(KSONItem::Property(_, KsonValue::Table(children), _)),
+                KSONItem::Property(_, KsonValue::Table(overlay), _),
+            ) => merge(children, overlay),
+            (Some(existing), item) => *existing = item,
+            (None, item) => base.push(item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{kson_items_to_json, Span};
+    use super::*;
+
+    fn property(key: &str, value: KsonValue) -> KSONItem {
+        KSONItem::Property(key.to_string(), value, Span::default())
+    }
+
+    fn section(key: &str, children: Vec<KSONItem>) -> KSONItem {
+        KSONItem::Section(key.to_string(), children, Span::default())
+    }
+
+    fn merged(mut base: Vec<KSONItem>, overlay: Vec<KSONItem>) -> String {
+        merge(&mut base, overlay);
+        kson_items_to_json(&base)
+    }
+
+    #[test]
+    fn replaces_values_and_adds_keys() {
+        let base = vec![
+            property("port", KsonValue::Integer(80)),
+            property("host", KsonValue::String("a".to_string())),
+        ];
+        let overlay = vec![
+            property("port", KsonValue::Integer(8080)),
+            property("debug", KsonValue::Bool(true)),
+        ];
+
+        assert_eq!(
+            merged(base, overlay),
+            r#"{"port": 8080,"host": "a","debug": true}"#
+        );
+    }
+
+    #[test]
+    fn merges_sections_key_by_key() {
+        let base = vec![section(
+            "db",
+            vec![
+                property("host", KsonValue::String("a".to_string())),
+                section("pool", vec![property("max", KsonValue::Integer(5))]),
+            ],
+        )];
+        let overlay = vec![section(
+            "db",
+            vec![section(
+                "pool",
+                vec![property("min", KsonValue::Integer(1))],
+            )],
+        )];
+
+        assert_eq!(
+            merged(base, overlay),
+            r#"{"db": {"host": "a","pool": {"max": 5,"min": 1}}}"#
+        );
+    }
+
+    #[test]
+    fn merges_inline_tables_with_sections() {
+        let base = vec![property(
+            "db",
+            KsonValue::Table(vec![property("host", KsonValue::String("a".to_string()))]),
+        )];
+        let overlay = vec![section("db", vec![property("port", KsonValue::Integer(1))])];
+
+        assert_eq!(merged(base, overlay), r#"{"db": {"host": "a","port": 1}}"#);
+
+        let base = vec![section("db", vec![property("port", KsonValue::Integer(1))])];
+        let overlay = vec![property(
+            "db",
+            KsonValue::Table(vec![property("port", KsonValue::Integer(2))]),
+        )];
+
+        assert_eq!(merged(base, overlay), r#"{"db": {"port": 2}}"#);
+    }
+
+    #[test]
+    fn replaces_arrays_whole() {
+        let base = vec![property(
+            "hosts",
+            KsonValue::Array(vec![KsonValue::Integer(1), KsonValue::Integer(2)]),
+        )];
+        let overlay = vec![property(
+            "hosts",
+            KsonValue::Array(vec![KsonValue::Integer(3)]),
+        )];
+
+        assert_eq!(merged(base, overlay), r#"{"hosts": [3]}"#);
+    }
+
+    #[test]
+    fn replaces_tables_with_other_values() {
+        let base = vec![section("db", vec![property("port", KsonValue::Integer(1))])];
+        let overlay = vec![property("db", KsonValue::Null)];
+
+        assert_eq!(merged(base, overlay), r#"{"db": null}"#);
+    }
+}
@@ -1,30 +1,107 @@
 #![allow(dead_code)]
 
 use colored::Colorize;
-use dotenvy::dotenv;
-use regex::Regex;
-use std::env;
-use std::fs::File;
-use std::io::{Read, Result};
-use std::process::exit;
-
-use crate::utils::kson;
+use std::collections::HashMap;
+use std::fs;
 
 use super::debug::{debug, warn};
 
+pub mod constraint;
+pub mod diagnostics;
+pub mod env;
+pub mod error;
+pub mod include;
+pub mod interpolate;
 pub mod kmodel;
-
-#[derive(Debug)]
+pub mod merge;
+pub mod overrides;
+pub mod profile;
+pub mod redact;
+pub mod secret;
+pub mod validate;
+pub mod value;
+
+pub use env::EnvSource;
+pub use error::{KsonError, KsonErrorKind, Span};
+pub use secret::{SecretProvider, SecretProviders};
+use value::json_string;
+pub use value::KsonValue;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum KSONItem {
-    Section(String, Vec<KSONItem>),
-    Property(String, String),
+    Section(String, Vec<KSONItem>, Span),
+    Property(String, KsonValue, Span),
 }
 
 pub struct KSON {
     pub properties: Vec<KSONItem>,
     pub _sections: Vec<String>,
     pub _section_indents: Vec<usize>, // Track indentation level for each section
-    pub env_vars: Vec<String>,
+    pub env_vars: Vec<EnvVar>,
+    /// Spans of the properties whose literal contains `${...}`.
+    pub _templates: Vec<Span>,
+    /// Spans of the properties read from an env variable or secret, with where
+    /// the text came from (`env var PORT`, `secret file:/run/secrets/db`).
+    pub _text_sources: Vec<(Span, String)>,
+    /// Spans of the properties whose value is masked in output, see [`redact`].
+    pub _sensitive: Vec<Span>,
+    /// Spans of the properties the KModel marks `@secret`, set by validation.
+    pub _secrets: Vec<Span>,
+    /// Header spans of the tables of each `$$name` array, by the span of the array.
+    pub _table_headers: Vec<(Span, Vec<Span>)>,
+    /// Spans of the properties whose value failed to read. They hold an empty
+    /// string so validation doesn't report them again as missing or mistyped.
+    pub _placeholders: Vec<Span>,
+}
+
+/// A variable declared with `@env(NAME)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvVar {
+    pub name: String,
+    /// `@env?(NAME)`: when unset, properties reading it are left out.
+    pub optional: bool,
+    /// `@env(NAME, default = value)`: used when the variable is unset.
+    pub default: Option<KsonValue>,
+}
+
+/// A parsed KSON document.
+pub type Document = KSON;
+
+/// How [`read`] and [`read_file`] treat a document.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// KModel to validate against; takes precedence over `@model`.
+    pub kmodel: Option<String>,
+    pub verbose: bool,
+    /// Accept a bare `@env` name as a value, as before `env(...)` existed, with a warning.
+    pub bare_env: bool,
+    /// Profile to apply: its `@profile(name)` blocks and `<file>.<name>.kson` overlay.
+    pub profile: Option<String>,
+    /// Files merged over the document, in order, after the profile overlay.
+    pub overlays: Vec<String>,
+    /// Apply `<prefix>__SECTION__KEY` env variables as overrides, see [`overrides`].
+    pub env_prefix: Option<String>,
+    /// Where env variables come from; the process environment by default.
+    pub env: EnvSource,
+    /// Providers for `@secret(scheme:...)` values, by scheme. Only `file` by default.
+    pub secrets: SecretProviders,
+    /// Print env and secret values in debug lines and diagnostics instead of `***`.
+    pub reveal: bool,
+    /// Policy for keys the KModel does not declare; overrides the model's `@strict`.
+    pub unknown_keys: Option<kmodel::UnknownKeys>,
+}
+
+/// State shared by a document and every file merged into it.
+struct Context {
+    model: Option<kmodel::KModel>,
+    includes: include::Includes,
+    env: HashMap<String, String>,
+}
+
+/// Where a dotted path lands: a section's items or a value.
+pub(crate) enum PathNode<'a> {
+    Items(&'a Vec<KSONItem>),
+    Value(&'a KsonValue),
 }
 
 impl KSON {
@@ -34,12 +111,18 @@ impl KSON {
             _sections: vec![],
             _section_indents: vec![],
             env_vars: vec![],
+            _templates: vec![],
+            _text_sources: vec![],
+            _sensitive: vec![],
+            _secrets: vec![],
+            _table_headers: vec![],
+            _placeholders: vec![],
         }
     }
 
-    pub fn get_property(&self, key: &str) -> Option<&str> {
+    pub fn get_property(&self, key: &str) -> Option<&KsonValue> {
         for item in &self.properties {
-            if let KSONItem::Property(k, v) = item {
+            if let KSONItem::Property(k, v, _) = item {
                 if k == key {
                     return Some(v);
                 }
@@ -49,31 +132,49 @@ impl KSON {
         None
     }
 
+    /// A section or inline table, addressed by a dotted path such as `database.replica`.
     pub fn get_section(&self, section: &str) -> Option<&Vec<KSONItem>> {
-        for item in &self.properties {
-            if let KSONItem::Section(k, v) = item {
-                if k == section {
-                    return Some(v);
-                }
-            }
+        match walk(&self.properties, section)?.0 {
+            PathNode::Items(items) => Some(items),
+            PathNode::Value(KsonValue::Table(items)) => Some(items),
+            PathNode::Value(_) => None,
         }
+    }
 
-        None
+    pub fn get_section_property(&self, section: &str, key: &str) -> Option<&KsonValue> {
+        self.get_path(&format!("{section}.{key}"))
     }
 
-    #[allow(unused)]
-    pub fn get_section_property(&self, section: &str, key: &str) -> Option<&str> {
-        if let Some(section) = self.get_section(section) {
-            for item in section {
-                if let KSONItem::Property(k, v) = item {
-                    if k == key {
-                        return Some(v);
-                    }
-                }
-            }
+    /// Look up a value by its dotted path, e.g. `database.replica.url`.
+    ///
+    /// Numeric segments index into arrays, so `replicas.0.host` reaches into
+    /// the first table of a `$$replicas` array.
+    pub fn get_path(&self, path: &str) -> Option<&KsonValue> {
+        match walk(&self.properties, path)?.0 {
+            PathNode::Value(value) => Some(value),
+            PathNode::Items(_) => None,
         }
+    }
 
-        None
+    /// Deep-merge `other` over this document, see [`merge::merge`].
+    pub fn absorb(&mut self, other: KSON) {
+        merge::merge(&mut self.properties, other.properties);
+        self.env_vars.extend(other.env_vars);
+        self._templates.extend(other._templates);
+        self._text_sources.extend(other._text_sources);
+        self._sensitive.extend(other._sensitive);
+        self._table_headers.extend(other._table_headers);
+        self._placeholders.extend(other._placeholders);
+    }
+
+    /// Mask the values of the properties the KModel marks `@secret`.
+    pub fn redact_secrets(&mut self) {
+        redact::secrets(&mut self.properties, &self._secrets);
+    }
+
+    /// The `@env` declaration of `name`.
+    pub fn env_var(&self, name: &str) -> Option<&EnvVar> {
+        self.env_vars.iter().find(|var| var.name == name)
     }
 
     fn last_section(&self) -> Option<&str> {
@@ -89,152 +190,503 @@ impl KSON {
         self._section_indents.pop();
     }
 
-    pub fn push_section(&mut self, section: &str, indent: usize) {
-        let new_section = KSONItem::Section(section.to_string(), vec![]);
+    /// Open `section`, reopening it if it already exists at this level (e.g.
+    /// because a dotted key created it). A dotted name opens each level in turn.
+    pub fn push_section(&mut self, section: &str, indent: usize, span: Span) {
+        for name in section.split('.') {
+            let container = self.container();
+
+            if !container
+                .iter()
+                .any(|item| matches!(item, KSONItem::Section(k, _, _) if k == name))
+            {
+                container.push(KSONItem::Section(name.to_string(), vec![], span));
+            }
 
-        if self._sections.is_empty() {
-            // Add to root level
-            self.properties.push(new_section);
-        } else {
-            // Add to the current nested section
-            let sections = self._sections.clone();
-            Self::add_to_nested_section(&mut self.properties, &sections, 0, new_section);
+            self._sections.push(name.to_string());
+            self._section_indents.push(indent);
         }
+    }
 
-        self._sections.push(section.to_string());
+    /// Open a new table in the `$$name` array of tables, creating the array on
+    /// its first occurrence.
+    pub fn push_table(&mut self, name: &str, indent: usize, span: Span) {
+        let container = self.container();
+        let position = container.iter().rposition(
+            |item| matches!(item, KSONItem::Property(k, KsonValue::Array(_), _) if k == name),
+        );
+
+        match position {
+            Some(position) => {
+                if let KSONItem::Property(_, KsonValue::Array(tables), array_span) =
+                    &mut container[position]
+                {
+                    tables.push(KsonValue::Table(vec![]));
+                    let array_span = *array_span;
+
+                    if let Some((_, headers)) = self
+                        ._table_headers
+                        .iter_mut()
+                        .find(|(s, _)| *s == array_span)
+                    {
+                        headers.push(span);
+                    }
+                }
+            }
+            None => {
+                container.push(KSONItem::Property(
+                    name.to_string(),
+                    KsonValue::Array(vec![KsonValue::Table(vec![])]),
+                    span,
+                ));
+                self._table_headers.push((span, vec![span]));
+            }
+        }
+
+        self._sections.push(name.to_string());
         self._section_indents.push(indent);
     }
 
     pub fn attr(&mut self, item: KSONItem) {
-        if self._sections.is_empty() {
-            // Add to root level
-            self.properties.push(item);
-        } else {
-            // Navigate to the correct nested section
-            let sections = self._sections.clone();
-            Self::add_to_nested_section(&mut self.properties, &sections, 0, item);
+        self.container().push(item);
+    }
+
+    /// Add the property `key = value`, below its prefix for a dotted key (see [`KSON::attr_at`]).
+    pub fn attr_key(&mut self, key: &str, value: KsonValue, span: Span) -> Result<(), String> {
+        match key.rsplit_once('.') {
+            Some((path, leaf)) => {
+                let path: Vec<&str> = path.split('.').collect();
+                self.attr_at(
+                    &path,
+                    KSONItem::Property(leaf.to_string(), value, span),
+                    span,
+                )
+            }
+            None => {
+                self.attr(KSONItem::Property(key.to_string(), value, span));
+                Ok(())
+            }
+        }
+    }
+
+    /// Stand in for the value of `key`, which failed to read and is reported already.
+    fn placeholder(&mut self, key: &str, span: Span) {
+        self._placeholders.push(span);
+        // A dotted key that conflicts is left out; its line has an error already.
+        let _ = self.attr_key(key, KsonValue::String(String::new()), span);
+    }
+
+    /// Add `item` below the dotted `path` (relative to the open section),
+    /// creating missing sections on the way: `a.b.c = 1` equals `c = 1` in `$a` / `$b`.
+    /// Fails with the path so far when it runs through a property that is not a table.
+    pub fn attr_at(&mut self, path: &[&str], item: KSONItem, span: Span) -> Result<(), String> {
+        let mut items = self.container();
+
+        for (depth, name) in path.iter().enumerate() {
+            let position = (0..items.len())
+                .rev()
+                .find(|&i| Self::children_mut(&mut items[i], name).is_some());
+
+            let position = match position {
+                Some(position) => position,
+                None if items
+                    .iter()
+                    .any(|item| matches!(item, KSONItem::Property(k, _, _) if k == name)) =>
+                {
+                    return Err(path[..=depth].join("."));
+                }
+                None => {
+                    items.push(KSONItem::Section(name.to_string(), vec![], span));
+                    items.len() - 1
+                }
+            };
+
+            items = Self::children_mut(&mut items[position], name).unwrap();
         }
+
+        items.push(item);
+        Ok(())
     }
 
-    fn add_to_nested_section(
-        properties: &mut Vec<KSONItem>,
-        sections: &[String],
-        depth: usize,
-        item: KSONItem,
-    ) {
-        if depth >= sections.len() {
-            properties.push(item);
-            return;
+    /// The list new items go into: the innermost open section, or the last
+    /// table of an open `$$` array of tables.
+    fn container(&mut self) -> &mut Vec<KSONItem> {
+        let mut items = &mut self.properties;
+
+        for name in &self._sections {
+            let position = (0..items.len())
+                .rev()
+                .find(|&i| Self::children_mut(&mut items[i], name).is_some());
+
+            match position {
+                Some(position) => items = Self::children_mut(&mut items[position], name).unwrap(),
+                // If we reach here, something went wrong
+                None => break,
+            }
         }
 
-        let target_section = &sections[depth];
+        items
+    }
 
-        // Find the matching section and navigate deeper
-        for prop in properties.iter_mut().rev() {
-            if let KSONItem::Section(section_name, ref mut section_props) = prop {
-                if section_name == target_section {
-                    Self::add_to_nested_section(section_props, sections, depth + 1, item);
-                    return;
+    fn children_mut<'a>(item: &'a mut KSONItem, name: &str) -> Option<&'a mut Vec<KSONItem>> {
+        match item {
+            KSONItem::Section(k, children, _) if k == name => Some(children),
+            KSONItem::Property(k, KsonValue::Table(children), _) if k == name => Some(children),
+            KSONItem::Property(k, KsonValue::Array(tables), _) if k == name => {
+                match tables.last_mut() {
+                    Some(KsonValue::Table(children)) => Some(children),
+                    _ => None,
                 }
             }
+            _ => None,
         }
+    }
+}
 
-        // If we reach here, something went wrong
-        properties.push(item);
+/// Follow a dotted `path` from `items`. Also returns the span of the last
+/// property passed, i.e. the property that owns the value found.
+pub(crate) fn walk<'a>(items: &'a Vec<KSONItem>, path: &str) -> Option<(PathNode<'a>, Span)> {
+    let mut node = PathNode::Items(items);
+    let mut owner = Span::default();
+
+    for segment in path.split('.') {
+        node = match node {
+            PathNode::Items(items) | PathNode::Value(KsonValue::Table(items)) => {
+                items.iter().rev().find_map(|item| match item {
+                    KSONItem::Section(k, children, _) if k == segment => {
+                        Some(PathNode::Items(children))
+                    }
+                    KSONItem::Property(k, value, span) if k == segment => {
+                        owner = *span;
+                        Some(PathNode::Value(value))
+                    }
+                    _ => None,
+                })?
+            }
+            PathNode::Value(KsonValue::Array(elements)) => {
+                PathNode::Value(elements.get(segment.parse::<usize>().ok()?)?)
+            }
+            PathNode::Value(_) => return None,
+        };
     }
+
+    Some((node, owner))
 }
 
-pub fn read_file(
-    file_path: &str,
-    kmodel_file: Option<&String>,
-    verbose: bool,
-) -> Result<Vec<KSONItem>> {
-    // Open the file
-    let mut file =
-        File::open(file_path).expect("FILE_NOT_FOUND".on_bright_red().to_string().as_str());
-    let mut text = String::new();
+/// The files merged over `file`, in order: the `<file>.<profile>.kson` of
+/// `profile` when it exists, then `overlays`.
+pub fn overlay_files(
+    file: Option<&str>,
+    profile: Option<&str>,
+    overlays: &[String],
+) -> Vec<String> {
+    let profile_overlay = match (profile, file) {
+        (Some(profile), Some(file)) => profile::overlay_file(file, profile),
+        _ => None,
+    };
+
+    profile_overlay
+        .into_iter()
+        .chain(overlays.iter().cloned())
+        .collect()
+}
 
-    file.read_to_string(&mut text)?;
+pub fn read_file(file_path: &str, options: &ReadOptions) -> Result<Document, Vec<KsonError>> {
+    let text = fs::read_to_string(file_path).map_err(|e| {
+        vec![KsonError::new(
+            KsonErrorKind::UnreadableFile(e.to_string()),
+            Span::new(0, 0),
+        )
+        .with_file(file_path)]
+    })?;
+
+    read_source(&text, Some(file_path), options)
+        .map_err(|errors| errors.into_iter().map(|e| e.with_file(file_path)).collect())
+}
 
-    Ok(read(&text, kmodel_file, verbose))
+pub fn read(text: &str, options: &ReadOptions) -> Result<Document, Vec<KsonError>> {
+    read_source(text, None, options)
 }
 
-pub fn read(text: &str, kmodel_file: Option<&String>, verbose: bool) -> Vec<KSONItem> {
-    let dotenv = dotenv();
+/// Read `text`; `file` is where it came from, if anywhere, to resolve `@include`.
+fn read_source(
+    text: &str,
+    file: Option<&str>,
+    options: &ReadOptions,
+) -> Result<Document, Vec<KsonError>> {
+    let verbose = options.verbose;
+    let reveal = options.reveal;
+
+    // Load kmodel once before the loop — loading inside the loop re-reads the file
+    // on every line, which is a significant performance and correctness bug.
+    let model: Option<kmodel::KModel> = match &options.kmodel {
+        Some(model) => {
+            debug(verbose, &format!("KModel (CLI): {}", model.bold().yellow()));
+            Some(kmodel::read(model, verbose).map_err(|e| vec![e])?)
+        }
+        None => None,
+    };
+
+    let mut ctx = Context {
+        model,
+        includes: include::Includes::new(file),
+        env: options.env.load()?,
+    };
+    let (mut kson, mut errors) = parse(text, file, options, &mut ctx, &[])?;
+
+    for overlay in &overlay_files(file, options.profile.as_deref(), &options.overlays) {
+        let (resolved, text) = match ctx.includes.enter(None, overlay) {
+            Ok(found) => found,
+            Err(kind) => {
+                errors.push(KsonError::new(kind, Span::new(0, 0)).with_file(overlay));
+                continue;
+            }
+        };
+
+        debug(
+            verbose,
+            &format!("Applying overlay {}", resolved.bold().yellow()),
+        );
+
+        let (child, child_errors) = parse_included(&text, &resolved, options, &mut ctx)?;
+        errors.extend(child_errors);
+        kson.absorb(child);
+    }
+
+    if let Some(prefix) = &options.env_prefix {
+        errors.extend(overrides::apply(
+            &mut kson, &ctx.env, prefix, verbose, reveal,
+        ));
+    }
 
-    if dotenv.is_err() {
-        warn(&format!(
-            "{}: {}. If you are using the {} keyword, an error may occur",
-            "MISSING_ENV_FILE",
-            dotenv.err().unwrap(),
-            "@env".bold().black()
+    errors.extend(interpolate::interpolate(
+        &mut kson, &ctx.env, verbose, reveal,
+    ));
+
+    if let Some(ksonmodel) = &ctx.model {
+        let unknown_keys = options
+            .unknown_keys
+            .or(ksonmodel.unknown_keys)
+            .unwrap_or_default();
+
+        errors.extend(validate::validate(
+            &mut kson,
+            ksonmodel,
+            unknown_keys,
+            verbose,
+            reveal,
         ));
     }
 
+    if !errors.is_empty() {
+        // Errors found after merging point into included files through their span.
+        let mut errors: Vec<KsonError> = errors
+            .into_iter()
+            .map(|e| match e.source {
+                0 => e,
+                n => e.with_file(&ctx.includes.files[n - 1]),
+            })
+            .collect();
+
+        errors.sort_by_key(|e| (e.file.clone(), e.line, e.column));
+        return Err(errors);
+    }
+
+    Ok(kson)
+}
+
+/// Parse the lines of one file, merging in the files it `@include`s.
+///
+/// `env_vars` are the `@env` declarations already in scope, those of the file
+/// around a `@profile` block.
+fn parse(
+    text: &str,
+    file: Option<&str>,
+    options: &ReadOptions,
+    ctx: &mut Context,
+    env_vars: &[EnvVar],
+) -> Result<(KSON, Vec<KsonError>), Vec<KsonError>> {
+    let verbose = options.verbose;
+    let reveal = options.reveal;
     let mut kson = KSON::new(vec![]);
+    kson.env_vars = env_vars.to_vec();
+    let mut errors: Vec<KsonError> = vec![];
+    let mut included: Vec<KSON> = vec![];
+    let mut profiles: Vec<KSON> = vec![];
 
-    // Load kmodel once before the loop — loading inside the loop re-reads the file
-    // on every line, which is a significant performance and correctness bug.
-    let mut ksonmodel: Option<kmodel::KModel> = kmodel_file.map(|model| {
-        debug(verbose, &format!("KModel (CLI): {}", model.bold().yellow()));
-        kmodel::read(model, verbose)
-    });
+    let lines: Vec<&str> = text.lines().collect();
+    let mut index = 0;
 
-    let kmodel_string = kson::kmodel::get_kmodel_colored();
-    let any_warn_emitted = &mut false;
+    while index < lines.len() {
+        let line_no = index + 1;
+        let line = strip_comment(lines[index]);
+        index += 1;
 
-    // Pre-compile regexes used inside the loop.
-    let array_re = Regex::new(r"\[(.*?)\]").unwrap();
-    let array_type_re = Regex::new(r"<([^<>]+)>").unwrap();
+        // Directives may be indented, e.g. inside a `@profile` block.
+        let directive = line.trim_start();
 
-    for line in text.lines() {
-        if line.starts_with("@model") {
-            // Only honour @model directive when no model was provided via CLI.
-            if ksonmodel.is_none() {
-                let model = line[6..].trim()
-                    .trim_start_matches('(')
-                    .trim_end_matches(')')
-                    .trim_start_matches('"')
-                    .trim_end_matches('"');
-                ksonmodel = Some(kmodel::read(model, verbose));
-                debug(verbose, &format!("KModel (@model): {}", model.bold().yellow()));
-            }
+        if directive.starts_with("@include") {
+            let span = Span::find(line_no, line, line.trim());
+
+            let Some(path) = include::directive(line) else {
+                errors.push(KsonError::new(
+                    KsonErrorKind::InvalidDirective(line.trim().to_string()),
+                    span,
+                ));
+                continue;
+            };
+
+            let (resolved, text) = match ctx.includes.enter(file, path) {
+                Ok(found) => found,
+                Err(kind) => {
+                    errors.push(KsonError::new(kind, span));
+                    continue;
+                }
+            };
+
+            debug(verbose, &format!("Including {}", resolved.bold().yellow()));
+
+            let (child, child_errors) = parse_included(&text, &resolved, options, ctx)?;
+            errors.extend(child_errors);
+            included.push(child);
             continue;
         }
 
-        if line.starts_with("@env") {
-            let env_name = line[4..].trim().trim_start_matches('(').trim_end_matches(')');
-            kson.env_vars.push(env_name.to_string());
-            let env_value = env::var(env_name).expect(&format!(
-                "{}: {}",
-                "MISSING_ENV".on_bright_red(),
-                env_name
-            ));
+        if directive.starts_with("@profile") {
+            let Some(name) = profile::directive(line) else {
+                errors.push(KsonError::new(
+                    KsonErrorKind::InvalidDirective(line.trim().to_string()),
+                    Span::find(line_no, line, line.trim()),
+                ));
+                continue;
+            };
+
+            // The block is every following blank or indented line.
+            let start = index;
+            while index < lines.len()
+                && (strip_comment(lines[index]).trim().is_empty()
+                    || lines[index].starts_with(char::is_whitespace))
+            {
+                index += 1;
+            }
+
+            if options.profile.as_deref() != Some(name) {
+                debug(
+                    verbose,
+                    &format!("Skipping profile {}", name.bold().black()),
+                );
+                continue;
+            }
 
             debug(
                 verbose,
-                &format!(
-                    "Registered env var: {} = {}",
-                    env_name.bold().black(),
-                    env_value.red()
-                ),
+                &format!("Applying profile {}", name.bold().yellow()),
             );
 
+            // Blank lines in place of the lines before the block keep line numbers right.
+            let block = "\n".repeat(start) + &lines[start..index].join("\n");
+            let (mut child, child_errors) = parse(&block, file, options, ctx, &kson.env_vars)?;
+            // The declarations of the file itself are already in `kson`.
+            child.env_vars.drain(..kson.env_vars.len());
+            errors.extend(child_errors);
+            profiles.push(child);
             continue;
         }
 
-        // Skip comments
-        if line.trim().starts_with('#') {
+        if directive.starts_with("@model") {
+            // Only honour @model directive when no model was provided via CLI.
+            if ctx.model.is_none() {
+                let model = model_directive(line);
+                ctx.model = Some(kmodel::read(model, verbose).map_err(|e| vec![e])?);
+                debug(
+                    verbose,
+                    &format!("KModel (@model): {}", model.bold().yellow()),
+                );
+            }
             continue;
         }
 
+        if directive.starts_with("@env") {
+            let Some((env_name, optional, default)) = parse_env_directive(line) else {
+                errors.push(KsonError::new(
+                    KsonErrorKind::InvalidDirective(line.trim().to_string()),
+                    Span::find(line_no, line, line.trim()),
+                ));
+                continue;
+            };
+
+            let default = match default {
+                Some(raw) => {
+                    let span = Span::find(line_no, line, raw);
+
+                    match KsonValue::parse(raw, span) {
+                        Some(value) => Some(value),
+                        None => {
+                            errors.push(KsonError::new(
+                                KsonErrorKind::InvalidValue {
+                                    key: env_name.to_string(),
+                                    raw: raw.to_string(),
+                                },
+                                span,
+                            ));
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let var = EnvVar {
+                name: env_name.to_string(),
+                optional,
+                default,
+            };
+
+            match (ctx.env.get(env_name), &var.default) {
+                (Some(value), _) => debug(
+                    verbose,
+                    &format!(
+                        "Registered env var: {} = {}",
+                        env_name.bold().black(),
+                        redact::redact(value, reveal).red()
+                    ),
+                ),
+                (None, Some(default)) => debug(
+                    verbose,
+                    &format!(
+                        "Registered env var: {} = {} (default)",
+                        env_name.bold().black(),
+                        redact::redact(&default.to_string(), reveal).red()
+                    ),
+                ),
+                (None, None) if optional => debug(
+                    verbose,
+                    &format!("Registered env var: {} (unset)", env_name.bold().black()),
+                ),
+                (None, None) => errors.push(KsonError::new(
+                    KsonErrorKind::MissingEnv(env_name.to_string()),
+                    Span::find(line_no, line, env_name),
+                )),
+            }
+
+            kson.env_vars.push(var);
+            continue;
+        }
+
+        // Skip blank and comment-only lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Calculate the indentation level of the line
+        let leading_whitespace = line.len() - line.trim_start().len();
+
         // Check if line contains a section (starts with $ after whitespace)
         let trimmed_line = line.trim_start();
         if trimmed_line.starts_with("$") {
-            let section = trimmed_line[1..].trim();
-
-            // Calculate indentation level of the section
-            let leading_whitespace = line.len() - line.trim_start().len();
+            // `$$name` opens a new table in the `name` array of tables.
+            let is_table = trimmed_line.starts_with("$$");
+            let section = trimmed_line.trim_start_matches('$').trim();
 
             debug(
                 verbose,
@@ -268,10 +720,41 @@ pub fn read(text: &str, kmodel_file: Option<&String>, verbose: bool) -> Vec<KSON
                 verbose,
                 &format!("Entering section: {}", section.bold().bright_cyan()),
             );
-            kson.push_section(section, leading_whitespace);
-        } else if let Some((key, mut value)) = parse_property_line(&line) {
-            // Calculate the indentation level of the property
-            let leading_whitespace = line.len() - line.trim_start().len();
+            let span = Span::find(line_no, line, section);
+
+            if is_table {
+                kson.push_table(section, leading_whitespace, span);
+            } else {
+                kson.push_section(section, leading_whitespace, span);
+            }
+        } else if let Some((key, mut raw)) = parse_property_line(&line) {
+            let span = Span::find(line_no, line, &raw);
+
+            if raw.starts_with("\"\"\"") && (raw.len() < 6 || !raw[3..].contains("\"\"\"")) {
+                match take_multiline_string(&lines, &mut index, &raw) {
+                    Some(joined) => raw = joined,
+                    None => {
+                        errors.push(KsonError::new(KsonErrorKind::UnterminatedString(key), span));
+                        continue;
+                    }
+                }
+            }
+
+            if (raw.starts_with('[') || raw.starts_with('{')) && open_brackets(&raw) > 0 {
+                match take_multiline_array(&lines, &mut index, &raw) {
+                    Some(joined) => raw = joined,
+                    None => {
+                        errors.push(KsonError::new(
+                            KsonErrorKind::Unclosed {
+                                key,
+                                delimiter: raw.chars().next().unwrap(),
+                            },
+                            span,
+                        ));
+                        continue;
+                    }
+                }
+            }
 
             // Exit sections if property is at the same or lesser indentation level
             while !kson._section_indents.is_empty() {
@@ -292,215 +775,402 @@ pub fn read(text: &str, kmodel_file: Option<&String>, verbose: bool) -> Vec<KSON
                 }
             }
 
-            if kson.env_vars.contains(&value.to_string()) {
-                let env_var = env::var(&value).expect(&format!(
-                    "{}: {}",
-                    "MISSING_ENV".on_bright_red(),
-                    value
-                ));
+            if line.starts_with(&key) && !kson._sections.is_empty() {
+                kson._sections.clear();
+            }
+
+            let sections: Vec<&str> = kson._sections.iter().map(String::as_str).collect();
+            let secret = ctx
+                .model
+                .as_ref()
+                .is_some_and(|model| redact::is_secret(model, &sections, &key));
+            // A malformed literal on a `@secret` property must not be printed either.
+            let masked = secret && !reveal;
+
+            let env_name = match env_call(&raw) {
+                Some(Some(name)) => Some(name.to_string()),
+                Some(None) => {
+                    errors.push(
+                        KsonError::new(
+                            KsonErrorKind::InvalidValue {
+                                key: key.clone(),
+                                raw: redact::redact(&raw, !masked),
+                            },
+                            span,
+                        )
+                        .with_masked(masked),
+                    );
+                    kson.placeholder(&key, span);
+                    continue;
+                }
+                None if kson.env_var(&raw).is_some() => {
+                    if !options.bare_env {
+                        errors.push(
+                            KsonError::new(
+                                KsonErrorKind::BareEnv {
+                                    key: key.clone(),
+                                    name: redact::redact(&raw, !masked),
+                                },
+                                span,
+                            )
+                            .with_masked(masked),
+                        );
+                        kson.placeholder(&key, span);
+                        continue;
+                    }
+
+                    warn(&format!(
+                        "{}: {} = {} reads an env var without env(); write env({}) instead",
+                        "BARE_ENV",
+                        key.bold().black(),
+                        raw,
+                        raw
+                    ));
+                    Some(raw.clone())
+                }
+                None => None,
+            };
+
+            let value = if let Some(env_name) = env_name {
+                let value = match env_value(&ctx.env, &kson.env_vars, &env_name) {
+                    Some(value) => value,
+                    None => match kson.env_var(&env_name) {
+                        Some(var) if var.optional => {
+                            debug(
+                                verbose,
+                                &format!(
+                                    "Leaving out {}: optional env var {} is unset",
+                                    key.bold().black(),
+                                    env_name.red()
+                                ),
+                            );
+                            continue;
+                        }
+                        // A missing declared variable is reported on its `@env` line.
+                        Some(_) => {
+                            kson.placeholder(&key, span);
+                            continue;
+                        }
+                        None => {
+                            errors.push(KsonError::new(KsonErrorKind::MissingEnv(env_name), span));
+                            kson.placeholder(&key, span);
+                            continue;
+                        }
+                    },
+                };
+
                 debug(
                     verbose,
                     &format!(
                         "{}: {} = {}",
                         "Replacing env var".yellow(),
-                        value.red(),
-                        env_var.red()
+                        env_name.red(),
+                        redact::redact(&value.to_string(), reveal).red()
                     ),
                 );
 
-                value = format!("\"{}\"", env_var);
-            }
+                // Env and secret text is converted to the KModel type during validation.
+                if let KsonValue::String(_) = value {
+                    kson._text_sources
+                        .push((span, format!("env var {env_name}")));
+                }
+                kson._sensitive.push(span);
+
+                value
+            } else if let Some(reference) = secret::call(&raw) {
+                let secret = match options.secrets.fetch(reference) {
+                    Ok(secret) => secret,
+                    Err(reason) => {
+                        errors.push(KsonError::new(
+                            KsonErrorKind::SecretUnavailable {
+                                key: key.clone(),
+                                reference: reference.to_string(),
+                                reason,
+                            },
+                            span,
+                        ));
+                        kson.placeholder(&key, span);
+                        continue;
+                    }
+                };
 
-            if line.starts_with(&key) && !kson._sections.is_empty() {
-                kson._sections.clear();
+                debug(
+                    verbose,
+                    &format!(
+                        "{}: {} = {}",
+                        "Fetched secret".yellow(),
+                        reference.red(),
+                        redact::redact(&secret, reveal).red()
+                    ),
+                );
+
+                kson._text_sources
+                    .push((span, format!("secret {reference}")));
+                kson._sensitive.push(span);
+                KsonValue::String(secret)
+            } else {
+                match KsonValue::parse(&raw, span) {
+                    Some(value) => value,
+                    None => {
+                        errors.push(
+                            KsonError::new(
+                                KsonErrorKind::InvalidValue {
+                                    key: key.clone(),
+                                    raw: redact::redact(&raw, !masked),
+                                },
+                                span,
+                            )
+                            .with_masked(masked),
+                        );
+                        kson.placeholder(&key, span);
+                        continue;
+                    }
+                }
+            };
+
+            if raw.contains("${") {
+                kson._templates.push(span);
+                // The entries of inline tables are expanded with the property.
+                kson._templates.extend(value.entry_spans());
             }
 
+            let sensitive = kson._sensitive.last() == Some(&span) || secret;
+            let shown = if sensitive {
+                redact::redact(&value.to_string(), reveal)
+            } else {
+                value.to_string()
+            };
+
             debug(
                 verbose,
-                &format!("Adding property: {} = {}", key.bold().black(), value.red()),
+                &format!("Adding property: {} = {}", key.bold().black(), shown.red()),
             );
 
-            if ksonmodel.is_some() {
-                let ksonmodel = ksonmodel.as_ref().unwrap();
+            if let Err(prefix) = kson.attr_key(&key, value, span) {
+                errors.push(KsonError::new(
+                    KsonErrorKind::KeyConflict {
+                        key: key.clone(),
+                        prefix,
+                    },
+                    Span::find(line_no, line, &key),
+                ));
+            }
+        }
+    }
 
-                let kt_value = if !kson._sections.is_empty() {
-                    ksonmodel.get_section_property(kson.last_section().unwrap(), &key)
-                } else {
-                    ksonmodel.get_property(&key)
-                };
+    if !included.is_empty() {
+        let own = std::mem::take(&mut kson.properties);
 
-                match kt_value {
-                    Some(kt_value) => {
-                        debug(
-                            verbose,
-                            &format!(
-                                "{}: {}: {}",
-                                kmodel_string,
-                                key.bold().black(),
-                                kt_value.to_string().red()
-                            ),
-                        );
+        for child in included {
+            kson.absorb(child);
+        }
 
-                        let kt_value = kt_value.to_string().trim_end_matches('?').to_string();
-                        let array_cap = array_re.captures_iter(&value).collect::<Vec<_>>();
+        merge::merge(&mut kson.properties, own);
+    }
 
-                        if kt_value == "Any" {
-                            if !*any_warn_emitted {
-                                warn(&format!(
-                                    "{} Use of Any type is not recommended",
-                                    kmodel_string
-                                ));
-                                *any_warn_emitted = true;
-                            }
+    for child in profiles {
+        kson.absorb(child);
+    }
 
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                            continue;
-                        }
+    Ok((kson, errors))
+}
 
-                        if value.starts_with('"') && value.ends_with('"') && kt_value == "String" {
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                        } else if value.starts_with('\'')
-                            && value.ends_with('\'')
-                            && kt_value == "Char"
-                        {
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                        } else if value.parse::<i32>().is_ok() && kt_value == "Integer" {
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                        } else if value.parse::<f32>().is_ok() && kt_value == "Float" {
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                        } else if value.parse::<bool>().is_ok() && kt_value == "Bool" {
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()));
-                        } else if !array_cap.is_empty() && kt_value.starts_with("Array") {
-                            let (_, [mut kind]) = array_type_re.captures(&kt_value).unwrap().extract();
-
-                            if kind.ends_with("?") {
-                                kind = &kind[..kind.len() - 1];
-                            }
-
-                            let mut index = 0;
-                            for v in array_cap {
-                                let value = &v[1];
-
-                                debug(verbose, &format!("Checking index {index} type"));
-
-                                index += 1;
-
-                                if kind.starts_with("Array") {
-                                    eprintln!("{}: Cannot put array inside array", kmodel_string);
-                                    exit(1);
-                                }
-
-                                if value.starts_with('"')
-                                    && value.ends_with('"')
-                                    && kind == "String"
-                                {
-                                    continue;
-                                } else if value.starts_with('\'')
-                                    && value.ends_with('\'')
-                                    && kind == "Char"
-                                {
-                                    continue;
-                                } else if value.parse::<i32>().is_ok() && kind == "Integer" {
-                                    continue;
-                                } else if value.parse::<f32>().is_ok() && kind == "Float" {
-                                    continue;
-                                } else if value.parse::<bool>().is_ok() && kind == "Bool" {
-                                    continue;
-                                }
-
-                                eprintln!("{}: Invalid value {} for {} at index[{}] of property: {}\nExpected value of type: {}", kmodel_string, value.red(), kt_value.bold().black(), index.to_string().bold(), key.bold().black(), kind.red());
-                                exit(1);
-                            }
-
-                            kson.attr(KSONItem::Property(key.clone(), value.clone()))
-                        } else {
-                            eprintln!("{}: Invalid value for property: {} = {}\nExpected value of type: {}", kmodel_string, key.bold().black(), value.red(), kt_value.red());
-                            exit(1);
-                        }
-                    }
-                    None => {}
-                }
-            } else {
-                kson.attr(KSONItem::Property(key, value));
-            }
+/// Parse a file opened with `ctx.includes.enter`, tagging its spans with a new source index.
+fn parse_included(
+    text: &str,
+    resolved: &str,
+    options: &ReadOptions,
+    ctx: &mut Context,
+) -> Result<(KSON, Vec<KsonError>), Vec<KsonError>> {
+    let (mut child, errors) = parse(text, Some(resolved), options, ctx, &[])?;
+    ctx.includes.leave();
+
+    ctx.includes.files.push(resolved.to_string());
+    include::retag(&mut child, ctx.includes.files.len());
+
+    Ok((
+        child,
+        errors.into_iter().map(|e| e.with_file(resolved)).collect(),
+    ))
+}
+
+/// Join the lines of a `"""` string opened by `first`, leaving `index` on the
+/// line after the closing quotes. Returns `None` if the string is never closed.
+pub(crate) fn take_multiline_string(
+    lines: &[&str],
+    index: &mut usize,
+    first: &str,
+) -> Option<String> {
+    let mut raw = first.to_string();
+
+    while *index < lines.len() {
+        let line = lines[*index];
+        *index += 1;
+        raw.push('\n');
+
+        if let Some(end) = line.find("\"\"\"") {
+            // Keep what follows the closing quotes (minus a comment) so that
+            // trailing garbage still fails to parse as a value.
+            raw.push_str(&line[..end + 3]);
+            raw.push_str(strip_comment(&line[end + 3..]));
+            return Some(raw);
         }
+
+        raw.push_str(line);
     }
 
-    if ksonmodel.is_some() {
-        let ksonmodel = ksonmodel.unwrap();
+    None
+}
 
-        for item in ksonmodel.properties {
-            match item {
-                kmodel::KItemType::Section(key, properties) => {
-                    let kson_section = if key.is_required() {
-                        let key = key.to_string();
+/// Join the lines of an array or inline table opened by `first` until its brackets balance,
+/// leaving `index` on the following line. Returns `None` if it is never closed.
+pub(crate) fn take_multiline_array(
+    lines: &[&str],
+    index: &mut usize,
+    first: &str,
+) -> Option<String> {
+    let mut raw = first.to_string();
+
+    while *index < lines.len() {
+        raw.push('\n');
+        raw.push_str(strip_comment(lines[*index]));
+        *index += 1;
+
+        if open_brackets(&raw) <= 0 {
+            return Some(raw);
+        }
+    }
 
-                        if kson.get_section(&key).is_none() {
-                            eprintln!(
-                                "{} Section {} is required by {}",
-                                "error".red(),
-                                key.to_string().bold().black(),
-                                kson::kmodel::get_kmodel_colored()
-                            );
-                            exit(1);
-                        }
+    None
+}
 
-                        kson.get_section(&key)
-                    } else {
-                        let key = key.to_string();
-
-                        kson.get_section(&key)
-                    };
-
-                    if kson_section.is_some() {
-                        let kson_section = kson_section.unwrap();
-
-                        for kitem in properties {
-                            if let kmodel::KItemType::Property(k, v) = kitem {
-                                let missing = kson_section
-                                    .iter()
-                                    .find(|&item| {
-                                        if let KSONItem::Property(k2, _) = item {
-                                            k2 == &k
-                                        } else {
-                                            false
-                                        }
-                                    })
-                                    .is_none();
-
-                                if missing && v.is_required() {
-                                    eprintln!(
-                                        "{} Property {} in {} is required by {}",
-                                        "error".red(),
-                                        k.bold().black(),
-                                        key.to_string().bold().bright_cyan(),
-                                        kson::kmodel::get_kmodel_colored()
-                                    );
-                                    exit(1);
-                                }
-                            }
-                        }
-                    }
-                }
-                kmodel::KItemType::Property(key, value) => {
-                    if kson.get_property(&key).is_none() && value.is_required() {
-                        eprintln!(
-                            "{} Property {} is required by {}",
-                            "error".red(),
-                            key.bold().black(),
-                            kson::kmodel::get_kmodel_colored()
-                        );
-                        exit(1);
-                    }
-                }
-            }
+/// Number of `[` / `{` in `raw` not yet closed, ignoring brackets inside strings.
+pub(crate) fn open_brackets(raw: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for c in raw.chars() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' || c == '{' => depth += 1,
+            None if c == ']' || c == '}' => depth -= 1,
+            None => {}
+        }
+    }
+
+    depth
+}
+
+/// Cut a trailing `# comment` from `line`. A `#` inside a quoted string is kept.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    strip_comment_quoted(line, &['"', '\''])
+}
+
+/// `line` without its `#` comment, skipping any `#` between a pair of `quotes`.
+pub(crate) fn strip_comment_quoted<'a>(line: &'a str, quotes: &[char]) -> &'a str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if quotes.contains(&c) => quote = Some(c),
+            None if c == '#' => return line[..i].trim_end(),
+            None => {}
         }
     }
 
-    kson.properties
+    line.trim_end()
+}
+
+/// The value of the env variable `name`, or its `@env` default when unset.
+pub(crate) fn env_value(
+    env: &HashMap<String, String>,
+    env_vars: &[EnvVar],
+    name: &str,
+) -> Option<KsonValue> {
+    match env.get(name) {
+        Some(value) => Some(KsonValue::String(value.clone())),
+        None => env_vars
+            .iter()
+            .find(|var| var.name == name)
+            .and_then(|var| var.default.clone()),
+    }
+}
+
+/// The path named by a `@model("path")` or `@model(path)` line.
+pub(crate) fn model_directive(line: &str) -> &str {
+    line.trim()[6..]
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim_start_matches('"')
+        .trim_end_matches('"')
+}
+
+/// The first `@model` path in `text`, if any.
+pub fn model_path(text: &str) -> Option<&str> {
+    text.lines()
+        .map(strip_comment)
+        .find(|line| line.starts_with("@model"))
+        .map(model_directive)
+}
+
+/// Split an `@env(NAME)`, `@env?(NAME)` or `@env(NAME, default = value)` line
+/// into the name, whether it is optional and the raw default.
+pub(crate) fn parse_env_directive(line: &str) -> Option<(&str, bool, Option<&str>)> {
+    let rest = line.trim().strip_prefix("@env")?;
+    let (optional, rest) = match rest.strip_prefix('?') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let rest = rest.trim();
+    let inner = match rest.strip_prefix('(') {
+        Some(inner) => inner.strip_suffix(')')?,
+        None => rest,
+    };
+
+    let (name, default) = match inner.split_once(',') {
+        Some((name, default)) => {
+            let (option, value) = default.split_once('=')?;
+            if option.trim() != "default" {
+                return None;
+            }
+            (name.trim(), Some(value.trim()))
+        }
+        None => (inner.trim(), None),
+    };
+
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    valid.then_some((name, optional, default))
+}
+
+/// The variable named by an `env(NAME)` or `env("NAME")` value: `None` when
+/// `raw` is not an `env(...)` call, `Some(None)` when the name is malformed.
+fn env_call(raw: &str) -> Option<Option<&str>> {
+    let inner = raw.strip_prefix("env(")?.strip_suffix(')')?.trim();
+    let name = match inner.strip_prefix('"') {
+        Some(quoted) => quoted.strip_suffix('"').unwrap_or(""),
+        None => inner,
+    };
+
+    let valid = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    Some(valid.then_some(name))
 }
 
-fn parse_property_line(line: &str) -> Option<(String, String)> {
+pub(crate) fn parse_property_line(line: &str) -> Option<(String, String)> {
     // Use splitn(2) so values containing '=' (e.g. base64, URLs) are preserved.
     let mut parts = line.splitn(2, '=');
     let key = parts.next()?.trim();
@@ -511,7 +1181,66 @@ fn parse_property_line(line: &str) -> Option<(String, String)> {
     Some((key.to_string(), value.to_string()))
 }
 
-pub fn kson_items_to_json(items: Vec<KSONItem>) -> String {
+/// Write `items` back as KSON text that reads to the same document.
+pub fn kson_items_to_kson(items: &[KSONItem]) -> String {
+    let mut lines = vec![];
+    write_kson(items, 0, &mut lines);
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn write_kson(items: &[KSONItem], depth: usize, lines: &mut Vec<String>) {
+    let indent = "    ".repeat(depth);
+
+    for item in items {
+        match item {
+            KSONItem::Section(key, children, _)
+            | KSONItem::Property(key, KsonValue::Table(children), _) => {
+                lines.push(format!("{indent}${key}"));
+                write_kson(children, depth + 1, lines);
+            }
+            KSONItem::Property(key, KsonValue::Array(tables), _)
+                if !tables.is_empty()
+                    && tables.iter().all(|t| matches!(t, KsonValue::Table(_))) =>
+            {
+                for table in tables {
+                    if let KsonValue::Table(children) = table {
+                        lines.push(format!("{indent}$${key}"));
+                        write_kson(children, depth + 1, lines);
+                    }
+                }
+            }
+            KSONItem::Property(key, value, _) => {
+                lines.push(format!("{indent}{key} = {}", kson_literal(value)));
+            }
+        }
+    }
+}
+
+/// `value` as a KSON literal. A `${` in a string is written `$${` so it is
+/// not expanded again.
+fn kson_literal(value: &KsonValue) -> String {
+    match value {
+        KsonValue::String(s) => format!("{:?}", s.replace("${", "$${")),
+        KsonValue::Array(elements) => {
+            let elements: Vec<String> = elements.iter().map(kson_literal).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        KsonValue::Table(items) => {
+            let items: Vec<String> = items
+                .iter()
+                .filter_map(|item| match item {
+                    KSONItem::Property(k, v, _) => Some(format!("{k} = {}", kson_literal(v))),
+                    KSONItem::Section(..) => None,
+                })
+                .collect();
+            format!("{{ {} }}", items.join(", "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+pub fn kson_items_to_json(items: &[KSONItem]) -> String {
     if items.is_empty() {
         return "{}".to_string();
     }
@@ -520,20 +1249,15 @@ pub fn kson_items_to_json(items: Vec<KSONItem>) -> String {
 
     for item in items {
         match item {
-            KSONItem::Property(key, value) => {
-                if value.starts_with('\'') && value.ends_with('\'') && value.len() >= 2 {
-                    json.push_str(&format!(
-                        "\"{}\": \"{}\",",
-                        key,
-                        &value[1..value.len() - 1]
-                    ));
-                    continue;
-                }
-
-                json.push_str(&format!("\"{}\": {},", key, value));
+            KSONItem::Property(key, value, _) => {
+                json.push_str(&format!("{}: {},", json_string(key), value.to_json()));
             }
-            KSONItem::Section(key, properties) => {
-                json.push_str(&format!("\"{}\": {},", key, kson_items_to_json(properties)));
+            KSONItem::Section(key, properties, _) => {
+                json.push_str(&format!(
+                    "{}: {},",
+                    json_string(key),
+                    kson_items_to_json(properties)
+                ));
             }
         }
     }
@@ -542,3 +1266,70 @@ pub fn kson_items_to_json(items: Vec<KSONItem>) -> String {
     json.push('}');
     json
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(text: &str) -> String {
+        let options = ReadOptions {
+            env: EnvSource::None,
+            ..ReadOptions::default()
+        };
+
+        match read(text, &options) {
+            Ok(kson) => kson_items_to_json(&kson.properties),
+            Err(errors) => panic!("{errors:?}"),
+        }
+    }
+
+    fn error(text: &str) -> KsonErrorKind {
+        let options = ReadOptions {
+            env: EnvSource::None,
+            ..ReadOptions::default()
+        };
+
+        match read(text, &options) {
+            Ok(kson) => panic!("read {}", kson_items_to_json(&kson.properties)),
+            Err(errors) => *errors[0].kind.clone(),
+        }
+    }
+
+    #[test]
+    fn adds_dotted_keys_to_sections() {
+        assert_eq!(
+            json("a.b.c = 1\n$a\n    x = 2\na.b.d = 3"),
+            r#"{"a": {"b": {"c": 1,"d": 3},"x": 2}}"#
+        );
+    }
+
+    #[test]
+    fn adds_dotted_keys_to_inline_tables() {
+        assert_eq!(json("a = { x = 1 }\na.y = 2"), r#"{"a": {"x": 1,"y": 2}}"#);
+        assert_eq!(
+            json("$$t\n    x = 1\nt.y = 2"),
+            r#"{"t": [{"x": 1,"y": 2}]}"#
+        );
+    }
+
+    #[test]
+    fn reports_dotted_keys_through_values() {
+        assert!(matches!(
+            error("a = 1\na.b = 2"),
+            KsonErrorKind::KeyConflict { prefix, .. } if prefix == "a"
+        ));
+        assert!(matches!(
+            error("a = { b = [1] }\na.b.c = 2"),
+            KsonErrorKind::KeyConflict { prefix, .. } if prefix == "a.b"
+        ));
+    }
+
+    #[test]
+    fn escapes_keys_in_json() {
+        assert_eq!(
+            json("p = { \"a\" = 1, \"q\\\"\" = 2 }"),
+            r#"{"p": {"a": 1,"q\"": 2}}"#
+        );
+        assert_eq!(json("say\"hi = 1"), r#"{"say\"hi": 1}"#);
+    }
+}
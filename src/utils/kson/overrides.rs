@@ -0,0 +1,147 @@
+#![allow(dead_code)]
+//! Twelve-factor overrides: with the prefix `APP`, the variable
+//! `APP__DATABASE__POOL_MAX=50` sets `database.pool_max`, creating it if needed.
+//!
+//! Path segments are matched case-insensitively against existing keys. The
+//! value keeps the type of the value it replaces when the text converts to it,
+//! and is otherwise text, converted to the KModel type like an `env(...)` value.
+
+use colored::Colorize;
+use std::collections::HashMap;
+
+use crate::utils::debug::debug;
+
+use super::error::{KsonError, KsonErrorKind, Span};
+use super::kmodel::KType;
+use super::redact::redact;
+use super::{KSONItem, KsonValue, KSON};
+
+/// Apply every `<prefix>__...` variable in `env` to `kson`.
+pub fn apply(
+    kson: &mut KSON,
+    env: &HashMap<String, String>,
+    prefix: &str,
+    verbose: bool,
+    reveal: bool,
+) -> Vec<KsonError> {
+    let mut errors = vec![];
+    let lead = format!("{prefix}__");
+
+    let mut vars: Vec<(String, String)> = env
+        .iter()
+        .filter(|(name, _)| name.starts_with(&lead))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    vars.sort();
+
+    for (ordinal, (name, value)) in vars.into_iter().enumerate() {
+        let path: Vec<String> = name[lead.len()..]
+            .split("__")
+            .map(|segment| segment.to_ascii_lowercase())
+            .collect();
+
+        // Overrides have no source line; the column only tells them apart.
+        let span = Span::new(0, ordinal + 1);
+
+        if path.iter().any(|segment| segment.is_empty())
+            || !set(&mut kson.properties, &path, &value, span)
+        {
+            errors.push(KsonError::new(
+                KsonErrorKind::BadOverride {
+                    name,
+                    path: path.join("."),
+                },
+                span,
+            ));
+            continue;
+        }
+
+        debug(
+            verbose,
+            &format!(
+                "{}: {} → {} = {}",
+                "Env override".yellow(),
+                name.bold().black(),
+                path.join(".").bold().black(),
+                redact(&value, reveal).red()
+            ),
+        );
+
+        kson._text_sources.push((span, format!("env var {name}")));
+        kson._sensitive.push(span);
+    }
+
+    errors
+}
+
+/// Set the value at `path`, creating missing sections. Returns false when the
+/// path runs through a value, or ends on a section or table.
+fn set(items: &mut Vec<KSONItem>, path: &[String], value: &str, span: Span) -> bool {
+    let Some((first, rest)) = path.split_first() else {
+        return false;
+    };
+
+    let position = items.iter().rposition(|item| match item {
+        KSONItem::Section(k, _, _) | KSONItem::Property(k, _, _) => k.eq_ignore_ascii_case(first),
+    });
+
+    let position = match position {
+        Some(position) => position,
+        None if rest.is_empty() => {
+            items.push(KSONItem::Property(
+                first.clone(),
+                KsonValue::String(value.to_string()),
+                span,
+            ));
+            return true;
+        }
+        None => {
+            items.push(KSONItem::Section(first.clone(), vec![], span));
+            items.len() - 1
+        }
+    };
+
+    match &mut items[position] {
+        KSONItem::Section(_, children, _)
+        | KSONItem::Property(_, KsonValue::Table(children), _)
+            if !rest.is_empty() =>
+        {
+            set(children, rest, value, span)
+        }
+        // Arrays are replaced whole; their text is split like an `env(...)` value.
+        KSONItem::Property(_, existing, existing_span)
+            if rest.is_empty() && !matches!(existing, KsonValue::Table(_)) =>
+        {
+            *existing = typed_like(existing, value);
+            *existing_span = span;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// `text` converted to the type of `existing`, so `port = 80` stays an integer
+/// without a KModel. Text that does not convert is kept as a string.
+fn typed_like(existing: &KsonValue, text: &str) -> KsonValue {
+    type_of(existing)
+        .and_then(|ktype| ktype.coerce(text))
+        .unwrap_or_else(|| KsonValue::String(text.to_string()))
+}
+
+fn type_of(value: &KsonValue) -> Option<KType> {
+    match value {
+        KsonValue::String(_) => Some(KType::String),
+        KsonValue::Integer(_) => Some(KType::Integer),
+        KsonValue::Float(_) => Some(KType::Float),
+        KsonValue::Bool(_) => Some(KType::Boolean),
+        KsonValue::Char(_) => Some(KType::Char),
+        KsonValue::Array(elements) => {
+            let element = match elements.first() {
+                Some(first) => type_of(first)?,
+                None => KType::String,
+            };
+            Some(KType::Array(Box::new(element)))
+        }
+        KsonValue::Table(_) | KsonValue::Null => None,
+    }
+}
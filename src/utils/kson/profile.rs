@@ -0,0 +1,40 @@
+#![allow(dead_code)]
+//! Profiles layer environment-specific values over a base document:
+//!
+//! ```text
+//! @profile(prod)
+//!     debug = false
+//!     $database
+//!         pool = 50
+//! ```
+//!
+//! Selecting a profile applies its `@profile(name)` blocks and the
+//! `<file>.<name>.kson` overlay next to the document, when there is one.
+
+use std::path::Path;
+
+/// The profile named by a `@profile(name)` line.
+pub(crate) fn directive(line: &str) -> Option<&str> {
+    let name = line
+        .trim()
+        .strip_prefix("@profile")?
+        .trim()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .trim();
+
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    valid.then_some(name)
+}
+
+/// The overlay file of `profile` for `file`, e.g. `app.prod.kson` for
+/// `app.kson`, if it exists.
+pub fn overlay_file(file: &str, profile: &str) -> Option<String> {
+    let overlay = format!("{}.{}.kson", file.trim_end_matches(".kson"), profile);
+
+    Path::new(&overlay).is_file().then_some(overlay)
+}
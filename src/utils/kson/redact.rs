@@ -0,0 +1,141 @@
+#![allow(dead_code)]
+//! Values from env variables and secret providers are shown as `***` in debug
+//! lines, diagnostics and `lson parse` output, so they stay out of CI logs.
+//! `--reveal` prints them as they are. Properties the KModel marks `@secret`
+//! are also masked in lockfiles and, on request, in JSON output.
+
+use super::kmodel::KModel;
+use super::{
+    open_brackets, parse_env_directive, parse_property_line, strip_comment, take_multiline_array,
+    take_multiline_string, KSONItem, KsonValue, Span,
+};
+
+/// What stands in for a sensitive value.
+pub const MASK: &str = "***";
+
+/// `value` as it may be printed.
+pub fn redact(value: &str, reveal: bool) -> String {
+    if reveal {
+        value.to_string()
+    } else {
+        MASK.to_string()
+    }
+}
+
+/// Replace the value of every property whose span is in `secrets` with `***`.
+pub fn secrets(items: &mut [KSONItem], secrets: &[Span]) {
+    for item in items {
+        match item {
+            KSONItem::Section(_, children, _) => self::secrets(children, secrets),
+            KSONItem::Property(_, value, span) if secrets.contains(span) => {
+                *value = KsonValue::String(MASK.to_string());
+            }
+            KSONItem::Property(_, value, _) => secrets_in(value, secrets),
+        }
+    }
+}
+
+fn secrets_in(value: &mut KsonValue, secrets: &[Span]) {
+    match value {
+        KsonValue::Array(elements) => elements
+            .iter_mut()
+            .for_each(|element| secrets_in(element, secrets)),
+        KsonValue::Table(items) => self::secrets(items, secrets),
+        _ => {}
+    }
+}
+
+/// KSON source text with the `@env(NAME, default = value)` defaults and the
+/// values of the properties `model` marks `@secret` masked.
+pub fn source(text: &str, model: Option<&KModel>) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut out = vec![];
+    // Open sections with their indentation, innermost last.
+    let mut sections: Vec<(usize, &str)> = vec![];
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+        let code = strip_comment(line);
+        index += 1;
+
+        if let Some((_, _, Some(default))) = parse_env_directive(code) {
+            let start = offset(line, default);
+            out.push(mask(line, start, start + default.len()));
+            continue;
+        }
+
+        let trimmed = code.trim_start();
+        let indent = code.len() - trimmed.len();
+
+        if trimmed.is_empty() || trimmed.starts_with('@') {
+            out.push(line.to_string());
+            continue;
+        }
+
+        while sections.last().is_some_and(|(i, _)| indent <= *i) {
+            sections.pop();
+        }
+
+        if trimmed.starts_with('$') {
+            sections.push((indent, trimmed.trim_start_matches('$').trim()));
+            out.push(line.to_string());
+            continue;
+        }
+
+        let path: Vec<&str> = sections.iter().map(|(_, section)| *section).collect();
+        let secret = match (model, parse_property_line(code)) {
+            (Some(model), Some((key, raw))) if is_secret(model, &path, &key) => Some(raw),
+            _ => None,
+        };
+
+        let Some(raw) = secret else {
+            out.push(line.to_string());
+            continue;
+        };
+
+        // A multi-line value is masked whole.
+        if raw.starts_with("\"\"\"") && (raw.len() < 6 || !raw[3..].contains("\"\"\"")) {
+            take_multiline_string(&lines, &mut index, &raw);
+        } else if (raw.starts_with('[') || raw.starts_with('{')) && open_brackets(&raw) > 0 {
+            take_multiline_array(&lines, &mut index, &raw);
+        }
+
+        let eq = code.find('=').unwrap_or(code.len() - 1);
+        let start = code.len() - code[eq + 1..].trim_start().len();
+        out.push(mask(line, start, code.len()));
+    }
+
+    out.join("\n")
+}
+
+/// Whether `key`, inside the open `sections`, is declared `@secret`.
+pub(crate) fn is_secret(model: &KModel, sections: &[&str], key: &str) -> bool {
+    // `a.b = 1` is `b` in section `a`.
+    let mut path = sections.to_vec();
+    let key = match key.rsplit_once('.') {
+        Some((prefix, key)) => {
+            path.push(prefix);
+            key
+        }
+        None => key,
+    };
+
+    let decl = if path.is_empty() {
+        model.get_property(key)
+    } else {
+        model.get_section_property(&path.join("."), key)
+    };
+
+    decl.is_some_and(|(ktype, _)| ktype.is_secret())
+}
+
+/// `line` with the bytes from `start` to `end` masked.
+fn mask(line: &str, start: usize, end: usize) -> String {
+    format!("{}{MASK}{}", &line[..start], &line[end..])
+}
+
+// `part` is a slice of `line`, so its address gives its offset.
+fn offset(line: &str, part: &str) -> usize {
+    part.as_ptr() as usize - line.as_ptr() as usize
+}
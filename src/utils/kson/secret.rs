@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+//! `@secret(scheme:reference)` values, fetched through a [`SecretProvider`]:
+//!
+//! ```text
+//! password = @secret(file:/run/secrets/db_password)
+//! token    = @secret("cmd:pass show api/token")
+//! api_key  = @secret(http://127.0.0.1:8200/secrets/api_key)
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{Read, Write},
+    net::TcpStream,
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A source of secret values, registered under a scheme such as `file`.
+pub trait SecretProvider: Send + Sync {
+    /// Fetch the secret named by `reference`, the text after `scheme:`.
+    fn fetch(&self, reference: &str) -> Result<String, String>;
+}
+
+/// The secret providers available to a document, by scheme. The default set
+/// has only `file`: `cmd` runs shell commands and `http` reaches the network,
+/// so a document can use them only once [`CommandProvider`] or [`HttpProvider`]
+/// is registered with [`SecretProviders::register`].
+#[derive(Clone)]
+pub struct SecretProviders {
+    providers: HashMap<String, Arc<dyn SecretProvider>>,
+}
+
+impl SecretProviders {
+    /// No providers at all: every `@secret` fails.
+    pub fn empty() -> SecretProviders {
+        SecretProviders {
+            providers: HashMap::new(),
+        }
+    }
+
+    /// Add `provider` for `scheme`, replacing any provider already registered for it.
+    pub fn register(&mut self, scheme: &str, provider: impl SecretProvider + 'static) {
+        self.providers
+            .insert(scheme.to_string(), Arc::new(provider));
+    }
+
+    /// Fetch `reference`, written `scheme:rest`.
+    pub fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (scheme, rest) = reference
+            .split_once(':')
+            .ok_or_else(|| "expected scheme:reference".to_string())?;
+
+        self.providers
+            .get(scheme)
+            .ok_or_else(|| match scheme {
+                "cmd" | "http" => format!(
+                    "the `{scheme}` secret provider is off by default, enable it with --allow-{scheme}-secrets"
+                ),
+                _ => format!("no secret provider for scheme `{scheme}`"),
+            })?
+            .fetch(rest)
+    }
+}
+
+impl Default for SecretProviders {
+    fn default() -> SecretProviders {
+        let mut providers = SecretProviders::empty();
+        providers.register("file", FileProvider);
+        providers
+    }
+}
+
+impl fmt::Debug for SecretProviders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut schemes: Vec<&String> = self.providers.keys().collect();
+        schemes.sort();
+        f.debug_tuple("SecretProviders").field(&schemes).finish()
+    }
+}
+
+/// `file:/run/secrets/name`: the contents of a file, without the trailing newline.
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        fs::read_to_string(reference)
+            .map(|text| text.trim_end_matches(['\n', '\r']).to_string())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// `cmd:pass show db`: the output of a shell command, without the trailing newline.
+pub struct CommandProvider;
+
+impl SecretProvider for CommandProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        let output = if cfg!(windows) {
+            Command::new("cmd").args(["/C", reference]).output()
+        } else {
+            Command::new("sh").args(["-c", reference]).output()
+        }
+        .map_err(|e| e.to_string())?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match stderr.trim() {
+                "" => format!("command exited with {}", output.status),
+                message => message.to_string(),
+            });
+        }
+
+        String::from_utf8(output.stdout)
+            .map(|text| text.trim_end_matches(['\n', '\r']).to_string())
+            .map_err(|_| "command output is not valid UTF-8".to_string())
+    }
+}
+
+/// `http://host:port/path`: the body of a plain-HTTP `GET`, meant for a
+/// secret store on localhost. There is no TLS support.
+pub struct HttpProvider;
+
+impl SecretProvider for HttpProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        let address = reference
+            .strip_prefix("//")
+            .ok_or_else(|| "expected http://host/path".to_string())?;
+        let (host, path) = match address.find('/') {
+            Some(slash) => (&address[..slash], &address[slash..]),
+            None => (address, "/"),
+        };
+        let target = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+
+        let mut stream = TcpStream::connect(&target).map_err(|e| format!("{target}: {e}"))?;
+        let timeout = Some(Duration::from_secs(10));
+        stream
+            .set_read_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+        stream
+            .set_write_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+
+        // HTTP/1.0 keeps the response free of chunked encoding.
+        write!(stream, "GET {path} HTTP/1.0\r\nHost: {host}\r\n\r\n").map_err(|e| e.to_string())?;
+
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| e.to_string())?;
+
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| "malformed HTTP response".to_string())?;
+        let status = head.lines().next().unwrap_or_default();
+
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(format!("server answered {status}"));
+        }
+
+        Ok(body.trim_end_matches(['\n', '\r']).to_string())
+    }
+}
+
+/// The reference of a `@secret(scheme:reference)` or `@secret("scheme:reference")` value.
+pub(crate) fn call(raw: &str) -> Option<&str> {
+    let inner = raw.strip_prefix("@secret(")?.strip_suffix(')')?.trim();
+
+    match inner.strip_prefix('"') {
+        Some(quoted) => quoted.strip_suffix('"'),
+        None => Some(inner),
+    }
+}
@@ -0,0 +1,608 @@
+#![allow(dead_code)]
+
+use colored::Colorize;
+
+use crate::utils::debug::{debug, warn};
+
+use super::error::{KsonError, KsonErrorKind, Span};
+use super::kmodel::{enum_values, get_kmodel_colored, KItemType, KModel, KType, UnknownKeys};
+use super::redact::redact;
+use super::{KSONItem, KsonValue, KSON};
+
+/// Check a parsed document against `model`, collecting every violation
+/// instead of stopping at the first one.
+///
+/// Properties and sections the model does not declare are handled by `unknown_keys`.
+/// `@secret` properties must be read from an env variable or secret provider.
+/// Properties left out get the model default, if they have one.
+pub fn validate(
+    kson: &mut KSON,
+    model: &KModel,
+    unknown_keys: UnknownKeys,
+    verbose: bool,
+    reveal: bool,
+) -> Vec<KsonError> {
+    let mut checker = Checker {
+        model,
+        unknown_keys,
+        text_sources: &kson._text_sources,
+        sensitive: &kson._sensitive,
+        table_headers: &kson._table_headers,
+        placeholders: &kson._placeholders,
+        verbose,
+        reveal,
+        secrets: vec![],
+        errors: vec![],
+        any_warn_emitted: false,
+    };
+
+    checker.check_items(&mut kson.properties, None);
+    checker.fill_defaults(&model.properties, &mut kson.properties, None);
+    checker.check_required(
+        &model.properties,
+        &[(&kson.properties, Span::new(0, 0))],
+        None,
+    );
+
+    // A plaintext secret must not reach the log through the source snippet either.
+    if !reveal {
+        for error in &mut checker.errors {
+            error.masked = checker.secrets.contains(&error.span());
+        }
+    }
+
+    kson._secrets = checker.secrets;
+    checker.errors
+}
+
+struct Checker<'a> {
+    model: &'a KModel,
+    unknown_keys: UnknownKeys,
+    text_sources: &'a [(Span, String)],
+    sensitive: &'a [Span],
+    table_headers: &'a [(Span, Vec<Span>)],
+    /// Spans of the properties whose value failed to read, see [`KSON::_placeholders`].
+    placeholders: &'a [Span],
+    verbose: bool,
+    reveal: bool,
+    /// Spans of the `@secret` properties checked so far.
+    secrets: Vec<Span>,
+    errors: Vec<KsonError>,
+    any_warn_emitted: bool,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, kind: KsonErrorKind, span: Span, note: &str, decl_span: Span) {
+        self.errors.push(KsonError::new(kind, span).with_note(
+            note,
+            self.model.file.as_deref(),
+            decl_span,
+        ));
+    }
+
+    /// `value`, masked if the property at `span` is sensitive.
+    fn shown(&self, value: &str, span: Span) -> String {
+        let sensitive = self.sensitive.contains(&span) || self.secrets.contains(&span);
+        redact(value, self.reveal || !sensitive)
+    }
+
+    fn check_items(&mut self, items: &mut [KSONItem], section: Option<&str>) {
+        for item in items {
+            match item {
+                KSONItem::Section(name, children, span) => {
+                    let path = join(section, name);
+
+                    if self.model.get_section(&path).is_some() {
+                        self.check_items(children, Some(&path));
+                    } else {
+                        self.unknown(section, name, true, *span);
+                    }
+                }
+                // The value failed to read and is reported already.
+                KSONItem::Property(key, _, span) if self.placeholders.contains(span) => {
+                    let decl = match section {
+                        Some(section) => self.model.get_section_property(section, key),
+                        None => self.model.get_property(key),
+                    };
+
+                    if decl.is_some_and(|(ktype, _)| ktype.is_secret()) {
+                        self.secrets.push(*span);
+                    }
+                }
+                KSONItem::Property(key, value, span)
+                    if self.model.get_section(&join(section, key)).is_some() =>
+                {
+                    // Inline tables and `$$` arrays of tables follow the section schema.
+                    let key = key.clone();
+                    let path = join(section, &key);
+
+                    match tables_mut(value) {
+                        Some(tables) => {
+                            for table in tables {
+                                self.check_items(table, Some(&path));
+                            }
+                        }
+                        None => self.errors.push(KsonError::new(
+                            KsonErrorKind::TypeMismatch {
+                                key: key.clone(),
+                                expected: "Table".to_string(),
+                                found: self.shown(&value.to_string(), *span),
+                            },
+                            *span,
+                        )),
+                    }
+                }
+                KSONItem::Property(key, value, span) => {
+                    let decl = match section {
+                        Some(section) => self.model.get_section_property(section, key),
+                        None => self.model.get_property(key),
+                    };
+
+                    let Some((ktype, decl_span)) = decl else {
+                        self.unknown(section, key, false, *span);
+                        continue;
+                    };
+
+                    if ktype.is_secret() {
+                        self.secrets.push(*span);
+
+                        if !self.sensitive.contains(span) {
+                            self.error(
+                                KsonErrorKind::PlaintextSecret(key.clone()),
+                                *span,
+                                "marked secret here",
+                                decl_span,
+                            );
+                        }
+                    }
+
+                    if self.coerce_text(key, value, *span, ktype, decl_span) {
+                        self.check_property(key, value, *span, ktype, decl_span);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply the unknown-key policy to `key`, which the model does not declare in `section`.
+    fn unknown(&mut self, section: Option<&str>, key: &str, is_section: bool, span: Span) {
+        let path = join(section, key);
+        let declared = match section {
+            Some(section) => self.model.get_section(section),
+            None => Some(&self.model.properties),
+        };
+        let suggestion = declared.and_then(|items| {
+            closest(
+                key,
+                items.iter().map(|item| match item {
+                    KItemType::Section(k, _, _) => k.to_string(),
+                    KItemType::Property(k, _, _) => k.clone(),
+                }),
+            )
+        });
+
+        match self.unknown_keys {
+            UnknownKeys::Strict => self.errors.push(KsonError::new(
+                KsonErrorKind::UnknownKey {
+                    path,
+                    section: is_section,
+                    suggestion,
+                },
+                span,
+            )),
+            UnknownKeys::Warn => warn(&format!(
+                "{}: {} is not declared in the KModel{}",
+                "UNKNOWN_KEY",
+                path.bold().black(),
+                suggestion
+                    .map(|s| format!(", did you mean {s}?"))
+                    .unwrap_or_default()
+            )),
+            UnknownKeys::Allow => debug(
+                self.verbose,
+                &format!("Passing through undeclared {}", path.bold().black()),
+            ),
+        }
+    }
+
+    /// Convert a value read from an env variable or secret to its declared type.
+    /// Returns false when the text is not a valid `ktype`.
+    fn coerce_text(
+        &mut self,
+        key: &str,
+        value: &mut KsonValue,
+        span: Span,
+        ktype: &KType,
+        decl_span: Span,
+    ) -> bool {
+        let Some((_, source)) = self.text_sources.iter().find(|(s, _)| *s == span) else {
+            return true;
+        };
+        let KsonValue::String(raw) = value else {
+            return true;
+        };
+
+        match ktype.coerce(raw) {
+            Some(coerced) => {
+                *value = coerced;
+                true
+            }
+            None => {
+                self.error(
+                    KsonErrorKind::BadSourceValue {
+                        key: key.to_string(),
+                        source: source.clone(),
+                        raw: self.shown(raw, span),
+                        expected: ktype.inner().to_string(),
+                    },
+                    span,
+                    "type declared here",
+                    decl_span,
+                );
+                false
+            }
+        }
+    }
+
+    fn check_property(
+        &mut self,
+        key: &str,
+        value: &KsonValue,
+        span: Span,
+        ktype: &KType,
+        decl_span: Span,
+    ) {
+        let kmodel_string = get_kmodel_colored();
+
+        debug(
+            self.verbose,
+            &format!(
+                "{}: {}: {}",
+                kmodel_string,
+                key.bold().black(),
+                ktype.to_string().red()
+            ),
+        );
+
+        if let KType::Any = ktype.inner() {
+            if !self.any_warn_emitted {
+                warn(&format!(
+                    "{} Use of Any type is not recommended",
+                    kmodel_string
+                ));
+                self.any_warn_emitted = true;
+            }
+        }
+
+        if let (KsonValue::Array(elements), KType::Array(kind)) = (value, ktype.inner()) {
+            self.check_elements(key, elements, kind, "", span, decl_span);
+            self.check_constraints(key, value, ktype, span, decl_span);
+        } else if let (false, KType::Enum(allowed)) = (ktype.accepts(value), ktype.inner()) {
+            self.not_in_enum(key, value, allowed, span, decl_span);
+        } else if !ktype.accepts(value) {
+            self.error(
+                KsonErrorKind::TypeMismatch {
+                    key: key.to_string(),
+                    expected: ktype.inner().to_string(),
+                    found: self.shown(&value.to_string(), span),
+                },
+                span,
+                "type declared here",
+                decl_span,
+            );
+        } else {
+            self.check_constraints(key, value, ktype, span, decl_span);
+        }
+    }
+
+    /// Report a value outside its `Enum`, with the closest allowed string.
+    fn not_in_enum(
+        &mut self,
+        key: &str,
+        value: &KsonValue,
+        allowed: &[KsonValue],
+        span: Span,
+        decl_span: Span,
+    ) {
+        let found = self.shown(&value.to_string(), span);
+
+        // A masked value gets no suggestion, which would give it away.
+        let suggestion = match value {
+            KsonValue::String(s) if found == value.to_string() => closest(
+                s,
+                allowed.iter().filter_map(|v| match v {
+                    KsonValue::String(a) => Some(a.clone()),
+                    _ => None,
+                }),
+            )
+            .map(|s| format!("{s:?}")),
+            _ => None,
+        };
+
+        self.error(
+            KsonErrorKind::NotInEnum {
+                key: key.to_string(),
+                found,
+                allowed: enum_values(allowed),
+                suggestion,
+            },
+            span,
+            "type declared here",
+            decl_span,
+        );
+    }
+
+    /// Report each constraint of `ktype` that `value` breaks.
+    fn check_constraints(
+        &mut self,
+        key: &str,
+        value: &KsonValue,
+        ktype: &KType,
+        span: Span,
+        decl_span: Span,
+    ) {
+        for constraint in ktype.constraints() {
+            if !constraint.allows(value) {
+                self.error(
+                    KsonErrorKind::ConstraintViolation {
+                        key: key.to_string(),
+                        constraint: constraint.to_string(),
+                        found: self.shown(&value.to_string(), span),
+                    },
+                    span,
+                    "constraint declared here",
+                    decl_span,
+                );
+            }
+        }
+    }
+
+    /// Check each element of an array, descending into nested arrays so the
+    /// error points at the innermost offending element.
+    fn check_elements(
+        &mut self,
+        key: &str,
+        elements: &[KsonValue],
+        kind: &KType,
+        path: &str,
+        span: Span,
+        decl_span: Span,
+    ) {
+        for (index, element) in elements.iter().enumerate() {
+            let path = format!("{path}[{index}]");
+            debug(self.verbose, &format!("Checking index {path} type"));
+
+            if let (KsonValue::Array(inner), KType::Array(inner_kind)) = (element, kind.inner()) {
+                self.check_elements(key, inner, inner_kind, &path, span, decl_span);
+                self.check_constraints(&format!("{key}{path}"), element, kind, span, decl_span);
+            } else if let (false, KType::Enum(allowed)) = (kind.accepts(element), kind.inner()) {
+                self.not_in_enum(&format!("{key}{path}"), element, allowed, span, decl_span);
+            } else if !kind.accepts(element) {
+                self.error(
+                    KsonErrorKind::BadArrayType {
+                        key: key.to_string(),
+                        index: path,
+                        expected: kind.to_string(),
+                        found: self.shown(&element.to_string(), span),
+                    },
+                    span,
+                    "type declared here",
+                    decl_span,
+                );
+            } else {
+                self.check_constraints(&format!("{key}{path}"), element, kind, span, decl_span);
+            }
+        }
+    }
+
+    /// Add the properties of `model_items` that have a default and are missing
+    /// from `items`, and from the tables in `items` of each section of `model_items`.
+    fn fill_defaults(
+        &mut self,
+        model_items: &[KItemType],
+        items: &mut Vec<KSONItem>,
+        section: Option<&str>,
+    ) {
+        for kitem in model_items {
+            match kitem {
+                KItemType::Property(key, ktype, _) => {
+                    let Some(default) = ktype.default() else {
+                        continue;
+                    };
+
+                    if items
+                        .iter()
+                        .any(|item| matches!(item, KSONItem::Property(k, _, _) if k == key))
+                    {
+                        continue;
+                    }
+
+                    debug(
+                        self.verbose,
+                        &format!(
+                            "{}: {} = {}",
+                            "Default from KModel".yellow(),
+                            join(section, key).bold().black(),
+                            default.to_string().red()
+                        ),
+                    );
+
+                    items.push(KSONItem::Property(
+                        key.clone(),
+                        default.clone(),
+                        Span::default(),
+                    ));
+                }
+                KItemType::Section(kmodel_section, properties, _) => {
+                    let name = kmodel_section.to_string();
+                    let path = join(section, &name);
+
+                    for item in items.iter_mut() {
+                        match item {
+                            KSONItem::Section(k, children, _) if *k == name => {
+                                self.fill_defaults(properties, children, Some(&path));
+                            }
+                            KSONItem::Property(k, value, _) if *k == name => {
+                                for table in tables_mut(value).unwrap_or_default() {
+                                    self.fill_defaults(properties, table, Some(&path));
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Report the required declarations of `model_items` missing from each
+    /// of `tables`, the tables matching the section at the dotted `section`.
+    fn check_required(
+        &mut self,
+        model_items: &[KItemType],
+        tables: &[(&Vec<KSONItem>, Span)],
+        section: Option<&str>,
+    ) {
+        for item in model_items {
+            match item {
+                KItemType::Section(kmodel_section, properties, decl_span) => {
+                    let name = kmodel_section.to_string();
+                    let path = join(section, &name);
+
+                    for (table, table_span) in tables {
+                        let kson_sections = find_sections(table, &name, self.table_headers);
+
+                        if kson_sections.is_empty() && kmodel_section.is_required() {
+                            self.error(
+                                KsonErrorKind::MissingSection(path.clone()),
+                                *table_span,
+                                "required by this declaration",
+                                *decl_span,
+                            );
+                        }
+
+                        self.check_required(properties, &kson_sections, Some(&path));
+                    }
+                }
+                KItemType::Property(key, value, decl_span) => {
+                    for (table, table_span) in tables {
+                        let missing = !table
+                            .iter()
+                            .any(|item| matches!(item, KSONItem::Property(k, _, _) if k == key));
+
+                        if missing && value.is_required() {
+                            self.error(
+                                KsonErrorKind::MissingProperty {
+                                    section: section.map(str::to_string),
+                                    key: key.clone(),
+                                },
+                                *table_span,
+                                "required by this declaration",
+                                *decl_span,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The name in `candidates` closest to `name`, if it is close enough to be a typo.
+pub(crate) fn closest(name: &str, candidates: impl Iterator<Item = String>) -> Option<String> {
+    let limit = (name.chars().count() / 3).max(1);
+
+    candidates
+        .map(|candidate| (distance(name, &candidate), candidate))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance between `a` and `b`, counting a swap of adjacent characters as one edit.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// `key` below the dotted path `section`.
+fn join(section: Option<&str>, key: &str) -> String {
+    match section {
+        Some(section) => format!("{section}.{key}"),
+        None => key.to_string(),
+    }
+}
+
+/// Every table named `key` at this level: `$key` sections, inline tables and
+/// each table of a `$$key` array of tables, the latter with the span of its header.
+fn find_sections<'a>(
+    items: &'a [KSONItem],
+    key: &str,
+    table_headers: &[(Span, Vec<Span>)],
+) -> Vec<(&'a Vec<KSONItem>, Span)> {
+    let mut sections = vec![];
+
+    for item in items {
+        match item {
+            KSONItem::Section(k, children, span) if k == key => sections.push((children, *span)),
+            KSONItem::Property(k, value, span) if k == key => match value {
+                KsonValue::Table(children) => sections.push((children, *span)),
+                KsonValue::Array(elements) => {
+                    let headers = table_headers
+                        .iter()
+                        .find(|(array, _)| array == span)
+                        .map(|(_, headers)| headers.as_slice())
+                        .unwrap_or_default();
+
+                    for (index, element) in elements.iter().enumerate() {
+                        if let KsonValue::Table(children) = element {
+                            sections.push((children, *headers.get(index).unwrap_or(span)));
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    sections
+}
+
+/// The tables held by an inline table or an array of tables.
+fn tables_mut(value: &mut KsonValue) -> Option<Vec<&mut Vec<KSONItem>>> {
+    match value {
+        KsonValue::Table(items) => Some(vec![items]),
+        KsonValue::Array(elements) => elements
+            .iter_mut()
+            .map(|element| match element {
+                KsonValue::Table(items) => Some(items),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
@@ -0,0 +1,538 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+use super::{kson_items_to_json, KSONItem, Span};
+
+/// A typed KSON value, produced once by the parser.
+///
+/// Validation and every output format work from this tree instead of
+/// re-guessing types from the raw source text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KsonValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Array(Vec<KsonValue>),
+    Table(Vec<KSONItem>),
+    Null,
+}
+
+impl KsonValue {
+    /// Parse the raw right-hand side of a `key = value` line.
+    ///
+    /// `span` is the location of the value; the entries of inline tables get
+    /// spans at their own position inside it. Returns `None` when the text is
+    /// not a valid KSON literal.
+    pub fn parse(raw: &str, span: Span) -> Option<KsonValue> {
+        let mut parser = ValueParser {
+            src: raw,
+            pos: 0,
+            span,
+        };
+
+        parser.skip_whitespace();
+        let value = parser.value()?;
+        parser.skip_whitespace();
+
+        if parser.pos == raw.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Parse a bare token such as `42`, `1.5`, `true` or `null`.
+    fn parse_scalar(token: &str) -> Option<KsonValue> {
+        match token {
+            "true" => return Some(KsonValue::Bool(true)),
+            "false" => return Some(KsonValue::Bool(false)),
+            "null" => return Some(KsonValue::Null),
+            _ => {}
+        }
+
+        if let Ok(i) = token.parse::<i64>() {
+            return Some(KsonValue::Integer(i));
+        }
+
+        // `f64::from_str` also accepts "inf" and "NaN", which are not KSON numbers.
+        let numeric = token
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'));
+
+        if numeric && token.chars().any(|c| c.is_ascii_digit()) {
+            if let Ok(f) = token.parse::<f64>() {
+                return Some(KsonValue::Float(f));
+            }
+        }
+
+        None
+    }
+
+    /// Spans of the entries of the inline tables in the value, at any depth.
+    pub fn entry_spans(&self) -> Vec<Span> {
+        match self {
+            KsonValue::Array(elements) => elements.iter().flat_map(Self::entry_spans).collect(),
+            KsonValue::Table(items) => items
+                .iter()
+                .flat_map(|item| match item {
+                    KSONItem::Property(_, value, span) => {
+                        let mut spans = vec![*span];
+                        spans.extend(value.entry_spans());
+                        spans
+                    }
+                    KSONItem::Section(..) => vec![],
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Name of the value's type, spelled the way KModel spells it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            KsonValue::String(_) => "String",
+            KsonValue::Integer(_) => "Integer",
+            KsonValue::Float(_) => "Float",
+            KsonValue::Bool(_) => "Bool",
+            KsonValue::Char(_) => "Char",
+            KsonValue::Array(_) => "Array",
+            KsonValue::Table(_) => "Table",
+            KsonValue::Null => "Null",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            KsonValue::String(s) => json_string(s),
+            KsonValue::Integer(i) => i.to_string(),
+            KsonValue::Float(f) => format!("{:?}", f),
+            KsonValue::Bool(b) => b.to_string(),
+            KsonValue::Char(c) => json_string(&c.to_string()),
+            KsonValue::Array(elements) => {
+                let elements = elements.iter().map(|e| e.to_json()).collect::<Vec<_>>();
+                format!("[{}]", elements.join(","))
+            }
+            KsonValue::Table(items) => kson_items_to_json(items),
+            KsonValue::Null => "null".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for KsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KsonValue::String(s) => write!(f, "{s:?}"),
+            KsonValue::Integer(i) => write!(f, "{i}"),
+            KsonValue::Float(v) => write!(f, "{v:?}"),
+            KsonValue::Bool(b) => write!(f, "{b}"),
+            KsonValue::Char(c) => write!(f, "{c:?}"),
+            KsonValue::Array(elements) => {
+                let elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            KsonValue::Table(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| match item {
+                        KSONItem::Property(k, v, _) => format!("{k} = {v}"),
+                        KSONItem::Section(k, _, _) => format!("${k}"),
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{{ {} }}", items.join(", "))
+            }
+            KsonValue::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// Recursive-descent parser for a single KSON value.
+struct ValueParser<'a> {
+    src: &'a str,
+    pos: usize,
+    span: Span,
+}
+
+impl<'a> ValueParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Option<KsonValue> {
+        match self.peek()? {
+            '"' if self.rest().starts_with("\"\"\"") => self.triple_string(),
+            '"' => self.string(),
+            '\'' => self.char(),
+            '[' => self.array(),
+            '{' => self.table(),
+            _ => self.scalar(),
+        }
+    }
+
+    /// Byte length of a quoted body ending at the first unescaped `quote`.
+    fn quoted_len(&self, quote: char) -> Option<usize> {
+        let mut escaped = false;
+
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                c if c == quote => return Some(i),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn string(&mut self) -> Option<KsonValue> {
+        self.quoted().map(KsonValue::String)
+    }
+
+    /// The unescaped body of a `"..."` string.
+    fn quoted(&mut self) -> Option<String> {
+        self.eat('"');
+        let len = self.quoted_len('"')?;
+        let body = unescape(&self.rest()[..len], false)?;
+        self.pos += len + 1;
+        Some(body)
+    }
+
+    /// A table key: a bare word, or a `"..."` string for keys with other characters.
+    fn key(&mut self) -> Option<String> {
+        if self.peek() == Some('"') {
+            return self.quoted().filter(|key| !key.is_empty());
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(self.rest().len());
+        let key = &self.rest()[..len];
+
+        if key.is_empty() || key.contains(['"', '\'', ',', '[', ']', '{', '}']) {
+            return None;
+        }
+
+        self.pos += len;
+        Some(key.to_string())
+    }
+
+    fn triple_string(&mut self) -> Option<KsonValue> {
+        self.pos += 3;
+        let len = self.rest().find("\"\"\"")?;
+        let body = &self.rest()[..len];
+
+        // A newline right after the opening quotes is not part of the string.
+        let body = body
+            .strip_prefix("\r\n")
+            .or_else(|| body.strip_prefix('\n'))
+            .unwrap_or(body);
+        let body = unescape(body, true)?;
+
+        self.pos += len + 3;
+        Some(KsonValue::String(body))
+    }
+
+    fn char(&mut self) -> Option<KsonValue> {
+        self.eat('\'');
+        let len = self.quoted_len('\'')?;
+        let body = unescape(&self.rest()[..len], true)?;
+        self.pos += len + 1;
+
+        let mut chars = body.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(KsonValue::Char(c)),
+            _ => None,
+        }
+    }
+
+    /// `[a, b, c]`, with nested arrays, trailing commas and newlines between elements.
+    fn array(&mut self) -> Option<KsonValue> {
+        self.eat('[');
+        let mut elements = vec![];
+
+        loop {
+            self.skip_whitespace();
+
+            if self.eat(']') {
+                return Some(KsonValue::Array(elements));
+            }
+
+            elements.push(self.value()?);
+            self.skip_whitespace();
+
+            if !self.eat(',') {
+                self.skip_whitespace();
+                return if self.eat(']') {
+                    Some(KsonValue::Array(elements))
+                } else {
+                    None
+                };
+            }
+        }
+    }
+
+    /// `{ key = value, ... }`, mapped to the same items as a `$section`.
+    fn table(&mut self) -> Option<KsonValue> {
+        self.eat('{');
+        let mut items = vec![];
+
+        loop {
+            self.skip_whitespace();
+
+            if self.eat('}') {
+                return Some(KsonValue::Table(items));
+            }
+
+            let key = self.key()?;
+            self.skip_whitespace();
+
+            if !self.eat('=') {
+                return None;
+            }
+
+            self.skip_whitespace();
+            let start = self.pos;
+            let value = self.value()?;
+            items.push(KSONItem::Property(key, value, self.span_of(start)));
+            self.skip_whitespace();
+
+            if !self.eat(',') {
+                self.skip_whitespace();
+                return if self.eat('}') {
+                    Some(KsonValue::Table(items))
+                } else {
+                    None
+                };
+            }
+        }
+    }
+
+    /// Span of the text from `start` to the current position, on its first line.
+    fn span_of(&self, start: usize) -> Span {
+        let before = &self.src[..start];
+        let (line, column) = match before.rfind('\n') {
+            Some(newline) => (
+                self.span.line + before.matches('\n').count(),
+                before[newline + 1..].chars().count() + 1,
+            ),
+            None => (self.span.line, self.span.column + before.chars().count()),
+        };
+        let text = &self.src[start..self.pos];
+        let text = text.split('\n').next().unwrap_or(text);
+
+        Span {
+            line,
+            column,
+            len: text.chars().count(),
+            source: self.span.source,
+        }
+    }
+
+    fn scalar(&mut self) -> Option<KsonValue> {
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || matches!(c, ',' | ']' | '}'))
+            .unwrap_or(self.rest().len());
+
+        if len == 0 {
+            return None;
+        }
+
+        let token = &self.rest()[..len];
+        self.pos += len;
+        KsonValue::parse_scalar(token)
+    }
+}
+
+/// Quote and escape `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+/// Resolve the escape sequences in the body of a quoted string.
+///
+/// Supports `\n`, `\t`, `\r`, `\0`, `\"`, `\'`, `\\` and `\u{XXXX}`. Returns `None`
+/// for an unknown escape, or for a bare `"` when `allow_quotes` is false.
+fn unescape(body: &str, allow_quotes: bool) -> Option<String> {
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '"' => '"',
+                '\'' => '\'',
+                '\\' => '\\',
+                'u' => {
+                    if chars.next()? != '{' {
+                        return None;
+                    }
+                    let mut hex = String::new();
+                    loop {
+                        match chars.next()? {
+                            '}' => break,
+                            c => hex.push(c),
+                        }
+                    }
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            '"' if !allow_quotes => return None,
+            c => out.push(c),
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Option<KsonValue> {
+        KsonValue::parse(raw, Span::new(1, 1))
+    }
+
+    #[test]
+    fn parses_scalars() {
+        assert_eq!(parse("42"), Some(KsonValue::Integer(42)));
+        assert_eq!(parse("-7"), Some(KsonValue::Integer(-7)));
+        assert_eq!(parse("1.5"), Some(KsonValue::Float(1.5)));
+        assert_eq!(parse("1e3"), Some(KsonValue::Float(1000.0)));
+        assert_eq!(parse("true"), Some(KsonValue::Bool(true)));
+        assert_eq!(parse("null"), Some(KsonValue::Null));
+        assert_eq!(parse("'x'"), Some(KsonValue::Char('x')));
+    }
+
+    #[test]
+    fn rejects_non_literals() {
+        assert_eq!(parse("inf"), None);
+        assert_eq!(parse("NaN"), None);
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("'ab'"), None);
+        assert_eq!(parse("\"open"), None);
+        assert_eq!(parse("1 2"), None);
+    }
+
+    #[test]
+    fn parses_strings() {
+        assert_eq!(
+            parse(r#""a\tb\u{41}""#),
+            Some(KsonValue::String("a\tbA".to_string()))
+        );
+        assert_eq!(
+            parse("\"\"\"\nsay \"hi\" now\"\"\""),
+            Some(KsonValue::String("say \"hi\" now".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_arrays() {
+        assert_eq!(
+            parse("[1, [2, 3],\n \"x\",\n]"),
+            Some(KsonValue::Array(vec![
+                KsonValue::Integer(1),
+                KsonValue::Array(vec![KsonValue::Integer(2), KsonValue::Integer(3)]),
+                KsonValue::String("x".to_string()),
+            ]))
+        );
+        assert_eq!(parse("[]"), Some(KsonValue::Array(vec![])));
+        assert_eq!(parse("[1 2]"), None);
+        assert_eq!(parse("[1,"), None);
+    }
+
+    #[test]
+    fn parses_tables() {
+        let value = parse(r#"{ host = "db", port = 5432, tls = { on = true } }"#).unwrap();
+
+        assert_eq!(
+            value.to_json(),
+            r#"{"host": "db","port": 5432,"tls": {"on": true}}"#
+        );
+        assert_eq!(parse("{}"), Some(KsonValue::Table(vec![])));
+        assert_eq!(parse("{ a = 1"), None);
+        assert_eq!(parse("{ a 1 }"), None);
+    }
+
+    #[test]
+    fn gives_table_entries_their_own_span() {
+        let value = parse("{ a = 1, b = 22 }").unwrap();
+
+        assert_eq!(
+            value.entry_spans(),
+            vec![
+                Span {
+                    line: 1,
+                    column: 7,
+                    len: 1,
+                    source: 0
+                },
+                Span {
+                    line: 1,
+                    column: 14,
+                    len: 2,
+                    source: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unquotes_table_keys() {
+        let value = parse(r#"{ "a" = 1, "two words" = 2, "q\"" = 3 }"#).unwrap();
+
+        assert_eq!(value.to_json(), r#"{"a": 1,"two words": 2,"q\"": 3}"#);
+    }
+
+    #[test]
+    fn rejects_malformed_table_keys() {
+        assert_eq!(parse(r#"{ a" = 1 }"#), None);
+        assert_eq!(parse("{ a,b = 1 }"), None);
+        assert_eq!(parse(r#"{ "" = 1 }"#), None);
+    }
+
+    #[test]
+    fn unescapes() {
+        assert_eq!(unescape(r"a\nb", false).as_deref(), Some("a\nb"));
+        assert_eq!(unescape(r#"\"\'\\\0"#, false).as_deref(), Some("\"'\\\0"));
+        assert_eq!(unescape(r"\u{1F600}", false).as_deref(), Some("😀"));
+        assert_eq!(unescape("say \"hi\"", true).as_deref(), Some("say \"hi\""));
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert_eq!(unescape(r"\q", false), None);
+        assert_eq!(unescape(r"\u{zz}", false), None);
+        assert_eq!(unescape(r"\u{41", false), None);
+        assert_eq!(unescape(r"\u41", false), None);
+        assert_eq!(unescape("trailing\\", false), None);
+        assert_eq!(unescape("say \"hi\"", false), None);
+    }
+}
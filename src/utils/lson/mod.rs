@@ -10,7 +10,7 @@
 //! LSON/1
 //! SALT:<32-hex-bytes>     (16 random bytes for Argon2id)
 //! NONCE:<24-hex-bytes>    (12 random bytes for ChaCha20-Poly1305)
-//! KSON-HASH:<64-hex>      (SHA-256 of the plaintext KSON — for drift detection)
+//! KSON-HASH:<64-hex>      (SHA-256 of the plaintext KSON and its includes — for drift detection)
 //!
 //! <base64-encoded-ciphertext-with-auth-tag>
 //! ```
@@ -25,6 +25,9 @@ use rand::{rngs::OsRng, RngCore};
 use sha2::{Digest, Sha256};
 use std::{fmt, fs, io};
 
+use super::kson::include::{source_hash, uses_include};
+use super::kson::{self, Document, KsonError, ReadOptions};
+
 const MAGIC: &str = "LSON/1";
 
 // Argon2id tuning: 64 MB memory, 3 iterations, 4 lanes → ~1 s on modern hardware
@@ -41,6 +44,8 @@ pub enum LsonError {
     DecryptionFailed,
     Io(io::Error),
     KeyDerivation(String),
+    /// The document could not be resolved before sealing.
+    Read(Vec<KsonError>),
 }
 
 impl fmt::Display for LsonError {
@@ -52,6 +57,10 @@ impl fmt::Display for LsonError {
             }
             LsonError::Io(e) => write!(f, "I/O error: {e}"),
             LsonError::KeyDerivation(m) => write!(f, "key derivation failed: {m}"),
+            LsonError::Read(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "cannot resolve KSON: {}", errors.join("; "))
+            }
         }
     }
 }
@@ -96,7 +105,35 @@ pub fn resolve_key(explicit: Option<&str>) -> Result<String, LsonError> {
 }
 
 /// Encrypt a KSON string and return the full LSON file content.
+///
+/// A document that `@include`s other files is sealed resolved, see [`seal_document`].
 pub fn encrypt(plaintext: &str, passphrase: &str) -> Result<String, LsonError> {
+    let kson_hash = sha256_hex(plaintext.as_bytes());
+
+    if uses_include(plaintext) {
+        let document = kson::read(plaintext, &ReadOptions::default()).map_err(LsonError::Read)?;
+        return seal_document(&document, &kson_hash, passphrase);
+    }
+
+    seal(plaintext, &kson_hash, passphrase)
+}
+
+/// Encrypt a resolved document, recording `kson_hash` as its KSON-HASH.
+///
+/// The included files do not travel with an LSON file, so the document is
+/// sealed as the KSON it resolves to: includes merged, references expanded
+/// and env values read.
+pub fn seal_document(
+    document: &Document,
+    kson_hash: &str,
+    passphrase: &str,
+) -> Result<String, LsonError> {
+    let plaintext = kson::kson_items_to_kson(&document.properties);
+    seal(&plaintext, kson_hash, passphrase)
+}
+
+/// Encrypt `plaintext`, recording `kson_hash` as its KSON-HASH.
+pub fn seal(plaintext: &str, kson_hash: &str, passphrase: &str) -> Result<String, LsonError> {
     let mut salt = [0u8; 16];
     let mut nonce_bytes = [0u8; 12];
     OsRng.fill_bytes(&mut salt);
@@ -111,8 +148,6 @@ pub fn encrypt(plaintext: &str, passphrase: &str) -> Result<String, LsonError> {
         .encrypt(nonce, plaintext.as_bytes())
         .map_err(|_| LsonError::DecryptionFailed)?;
 
-    let kson_hash = sha256_hex(plaintext.as_bytes());
-
     Ok(format!(
         "{magic}\nSALT:{salt}\nNONCE:{nonce}\nKSON-HASH:{hash}\n\n{data}\n",
         magic = MAGIC,
@@ -144,9 +179,18 @@ pub fn kson_hash_from_lson(lson_content: &str) -> Result<String, LsonError> {
     Ok(parse_lson(lson_content)?.kson_hash)
 }
 
+/// Encrypt a KSON file. Its KSON-HASH covers the files it `@include`s as well,
+/// and a file that includes others is sealed resolved, see [`seal_document`].
 pub fn encrypt_file(path: &str, passphrase: &str) -> Result<String, LsonError> {
     let text = fs::read_to_string(path)?;
-    encrypt(&text, passphrase)
+    let kson_hash = source_hash(path, &[]).map_err(|e| LsonError::Read(vec![e]))?;
+
+    if uses_include(&text) {
+        let document = kson::read_file(path, &ReadOptions::default()).map_err(LsonError::Read)?;
+        return seal_document(&document, &kson_hash, passphrase);
+    }
+
+    seal(&text, &kson_hash, passphrase)
 }
 
 pub fn decrypt_file(path: &str, passphrase: &str) -> Result<String, LsonError> {